@@ -1,39 +1,155 @@
-use anyhow::Ok;
 use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use core::fmt;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use serde::Deserialize;
+use futures::{stream, Stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, LINK, RETRY_AFTER},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
-use std::{fs::File, io::Write};
+use tokio::{fs::File, io::AsyncWriteExt};
+use tracing::{debug, info, warn};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::remores::{Booking, TIMEZONE};
+
+pub const DEFAULT_API_URL: &str = "https://canvas.kth.se/api/v1";
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many files of a submission are downloaded at the same time by
+/// default.
+pub const DEFAULT_ATTACHMENT_CONCURRENCY: usize = 3;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// The longest a `Retry-After` header can make a retry wait, so that a
+/// bogus value doesn't stall the download.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Below this much remaining Canvas rate limit budget, requests are slowed
+/// down, by up to `MAX_THROTTLE_DELAY` as the budget runs out.
+const RATE_LIMIT_THRESHOLD: f64 = 200.0;
+const MAX_THROTTLE_DELAY: Duration = Duration::from_secs(5);
+
+/// The most a downloaded zip archive is extracted to, since the archives
+/// come from students and a small one can unpack to a huge size.
+const MAX_EXTRACTED_BYTES: u64 = 1 << 30;
 
-use crate::remores::{Booking, Email};
+/// Errors talking to Canvas.
+#[derive(Debug, thiserror::Error)]
+pub enum CanvasError {
+    #[error("Invalid Canvas base URL {url}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("Canvas rejected the API token — generate a new one at {settings_url}")]
+    Unauthorized { settings_url: String },
+    /// The token has characters that can't be sent in a header, like a
+    /// line break.
+    #[error("The Canvas API token contains invalid characters, e.g. a line break")]
+    InvalidToken,
+    /// Canvas kept rate limiting (429) or failing (5xx) after all retries.
+    #[error("Canvas request to {url} failed with status {status} after {retries} retries")]
+    RateLimited {
+        url: String,
+        status: StatusCode,
+        retries: u32,
+    },
+    #[error("No such course {0} on Canvas, or you don't have access to it")]
+    CourseNotFound(u32),
+    #[error("No such assignment {assignment} in course {course} on Canvas")]
+    AssignmentNotFound { course: u32, assignment: u32 },
+    #[error("Canvas request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid mapping file {path}: {reason}")]
+    Parse { path: PathBuf, reason: String },
+    #[error("Invalid fixture {path}: {reason}")]
+    Fixture { path: PathBuf, reason: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("No attachments, text or link found for submission")]
+    NoContent,
+    /// The signed download URL of an attachment is no longer valid.
+    #[error("the download link has expired")]
+    ExpiredUrl,
+    #[error("expected {expected} bytes but received {received}")]
+    Incomplete { expected: u64, received: u64 },
+    #[error("Failed to extract {path}: {reason}")]
+    Extract { path: PathBuf, reason: String },
+    #[error("Failed to download {name}: {source}")]
+    Download {
+        name: String,
+        source: Box<CanvasError>,
+    },
+    /// Some files of a submission could not be downloaded, `files` are the
+    /// ones that were.
+    #[error("{}", join_errors(.errors))]
+    FilesFailed {
+        files: Vec<DownloadedFile>,
+        errors: Vec<CanvasError>,
+    },
+}
 
-const API_URL: &str = "https://canvas.kth.se/api/v1";
+fn join_errors(errors: &[CanvasError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
 
 pub struct Canvas {
     client: reqwest::Client,
+    base_url: String,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    timeout: Duration,
+    /// Read responses from files in this folder instead of Canvas.
+    fixtures: Option<PathBuf>,
+    download_progress: Option<Arc<dyn DownloadProgress>>,
+}
+
+/// Told about the bytes of each attachment as it downloads, e.g. to show a
+/// progress bar per file. Attachments download concurrently, so calls for
+/// different paths are interleaved.
+pub trait DownloadProgress: Send + Sync {
+    /// A download of `len` bytes, if known, to `path` has started.
+    fn start(&self, path: &Path, len: Option<u64>);
+    /// `bytes` more bytes were written to `path`.
+    fn advance(&self, path: &Path, bytes: u64);
+    /// The download to `path` has finished or failed.
+    fn finish(&self, path: &Path);
 }
 
-#[derive(Deserialize, Debug)]
-struct Enrollment {
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Enrollment {
     r#type: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Course {
     pub name: String,
     pub id: u64,
     created_at: Option<DateTime<Utc>>,
+    /// Only listed when fetching all courses.
+    #[serde(default)]
     enrollments: Vec<Enrollment>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Assignment {
     pub id: u64,
     pub name: String,
+    /// Set for group assignments.
+    group_category_id: Option<u64>,
     due_at: Option<DateTime<Utc>>,
     published: bool,
     grading_type: String,
@@ -43,196 +159,2428 @@ pub struct Assignment {
 pub struct Submission {
     pub id: u64,
     attachments: Option<Vec<Attachment>>,
+    /// The HTML text of an `online_text_entry` submission.
+    body: Option<String>,
+    /// The link of an `online_url` submission.
+    url: Option<String>,
+    /// The grade as shown in Canvas, e.g. `complete` or `B`.
+    pub grade: Option<String>,
+    pub score: Option<f64>,
+    /// E.g. `submitted`, `graded` or `unsubmitted`.
+    pub workflow_state: Option<String>,
+    /// Whether the submission was made after the due date. Missing for
+    /// assignments without one.
+    pub late: Option<bool>,
+    /// How long after the due date the submission was made.
+    pub seconds_late: Option<u64>,
+    /// The group of the student, when `include[]=group` is requested for
+    /// a group assignment.
+    pub group: Option<Group>,
+    /// Every attempt of the submission, when `include[]=submission_history`
+    /// is requested.
+    submission_history: Option<Vec<SubmissionVersion>>,
     pub user: User,
 }
 
+impl Submission {
+    /// The uploaded files, empty for text and link submissions.
+    pub fn attachments(&self) -> &[Attachment] {
+        self.attachments.as_deref().unwrap_or_default()
+    }
+
+    pub fn is_late(&self) -> bool {
+        self.late.unwrap_or(false)
+    }
+
+    /// Whether the student has submitted anything. Canvas lists an empty
+    /// `unsubmitted` placeholder for every student who hasn't.
+    pub fn is_submitted(&self) -> bool {
+        self.workflow_state.as_deref() != Some("unsubmitted")
+    }
+
+    /// Whether the `workflow_state` is one of `states`, which allows any
+    /// state when empty.
+    pub fn in_states(&self, states: &[String]) -> bool {
+        states.is_empty()
+            || self
+                .workflow_state
+                .as_deref()
+                .is_some_and(|state| states.iter().any(|s| s == state))
+    }
+}
+
+/// One attempt in the history of a submission.
 #[derive(Deserialize, Debug, Clone)]
+pub struct SubmissionVersion {
+    pub attempt: Option<u32>,
+    pub submitted_at: Option<DateTime<Utc>>,
+    attachments: Option<Vec<Attachment>>,
+    body: Option<String>,
+    url: Option<String>,
+}
+
+impl SubmissionVersion {
+    fn content(&self) -> Content<'_> {
+        Content {
+            attachments: self.attachments.as_deref().unwrap_or_default(),
+            body: self.body.as_deref(),
+            url: self.url.as_deref(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Group {
+    pub id: Option<u64>,
+    pub name: Option<String>,
+}
+
+impl Assignment {
+    pub fn is_group_assignment(&self) -> bool {
+        self.group_category_id.is_some()
+    }
+
+    pub fn due_at(&self) -> Option<DateTime<Utc>> {
+        self.due_at
+    }
+
+    pub fn published(&self) -> bool {
+        self.published
+    }
+
+    /// E.g. `pass_fail`, `points` or `letter_grade`.
+    pub fn grading_type(&self) -> &str {
+        &self.grading_type
+    }
+}
+
+impl Course {
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.created_at
+    }
+
+    /// Your enrollments in the course. Only set by `get_courses`.
+    pub fn enrollments(&self) -> &[Enrollment] {
+        &self.enrollments
+    }
+}
+
+impl Enrollment {
+    /// E.g. `teacher`, `ta` or `student`.
+    pub fn enrollment_type(&self) -> &str {
+        &self.r#type
+    }
+}
+
+impl Submission {
+    /// Whether there are any attachments, text or link to download.
+    fn has_content(&self) -> bool {
+        self.attachments.as_ref().is_some_and(|a| !a.is_empty())
+            || self.body.as_ref().is_some_and(|b| !b.trim().is_empty())
+            || self.url.as_ref().is_some_and(|u| !u.trim().is_empty())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
     pub name: String,
-    #[serde(rename = "login_id")]
-    email: String,
+    #[serde(rename = "login_id", default)]
+    email: Option<String>,
+    /// Set for accounts from the student register, some of which, like
+    /// test students, have no login.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sis_user_id: Option<String>,
+}
+
+impl User {
+    /// The Canvas login, which for KTH students is their KTH email. Empty
+    /// for accounts without a login.
+    pub fn email(&self) -> &str {
+        self.email.as_deref().unwrap_or_default()
+    }
+
+    /// The login, or the SIS id for accounts without a login.
+    pub fn identifier(&self) -> &str {
+        match self.email() {
+            "" => self.sis_user_id.as_deref().unwrap_or_default(),
+            email => email,
+        }
+    }
 }
 
 impl fmt::Display for User {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ({})", self.name, self.email)
+        write!(f, "{} ({})", self.name, self.identifier())
     }
 }
 
 #[derive(Deserialize, Debug, Clone)]
-struct Attachment {
+pub struct Attachment {
+    /// The Canvas file id, used to get a fresh `url` when it has expired.
+    id: Option<u64>,
     url: String,
     display_name: String,
+    /// The size of the file in bytes.
+    size: Option<u64>,
+    /// The MIME type, e.g. `application/pdf`.
+    #[serde(rename = "content-type")]
+    content_type: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+impl Attachment {
+    /// The signed download URL of the file.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The name of the file as uploaded.
+    pub fn display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    /// The size of the file in bytes, as reported by Canvas.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// When the file was uploaded.
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.created_at
+    }
+}
+
+/// The default Jaro similarity a student name must exceed to be accepted
+/// as a fuzzy match for a booking.
+pub const DEFAULT_FUZZY_THRESHOLD: f64 = 0.8;
+
+/// Fuzzy matches whose best two candidates are closer than this are
+/// reported as ambiguous.
+const AMBIGUITY_DELTA: f64 = 0.05;
+
+/// A submission matched to a booking, and how it was found.
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub submission: Submission,
+    pub method: MatchMethod,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum MatchMethod {
+    /// The booking was mapped to the Canvas login in a mapping file.
+    Manual,
+    /// The booking email equals the Canvas login.
+    Email,
+    /// The part of the booking email before the `@` equals that of the
+    /// Canvas login, e.g. `x@ug.kth.se` and `x@kth.se`.
+    LocalPart,
+    /// The student name is similar to the booking name. `ambiguous` is set
+    /// when another submission was almost as similar.
+    Fuzzy { score: f64, ambiguous: bool },
+}
+
+/// Options controlling which assignments `get_assignments` lists. By
+/// default only published assignments with a pass/fail, points or letter
+/// grade are included.
+#[derive(Debug, Clone, Default)]
+pub struct AssignmentOptions {
+    /// Also include draft assignments.
+    pub include_unpublished: bool,
+    /// Also include assignments with other grading types, e.g. `gpa_scale`
+    /// or `not_graded`.
+    pub all_grading_types: bool,
+}
+
+/// Options controlling how bookings are matched to submissions.
+#[derive(Debug, Clone)]
+pub struct MatchOptions {
+    /// Minimum name similarity, between 0.0 and 1.0, for a fuzzy match.
+    pub fuzzy_threshold: f64,
+    /// Manual overrides from a booking name or email to a Canvas login,
+    /// consulted before any automatic matching.
+    pub mapping: HashMap<String, String>,
+    /// Treat the assignment as a group assignment, where a booking gets the
+    /// files of their group even if another member uploaded them.
+    pub group: bool,
+    /// Extra fields to fetch with the submissions.
+    pub include: SubmissionIncludes,
+}
+
+/// Which `include[]` fields to request with submissions. The `user` is
+/// always included, since bookings are matched on it.
+#[derive(Debug, Clone, Default)]
+pub struct SubmissionIncludes {
+    /// The group of each student, for group assignments.
+    pub group: bool,
+    /// Every earlier attempt of each submission.
+    pub submission_history: bool,
+}
+
+impl SubmissionIncludes {
+    fn params(&self) -> Vec<(&'static str, &'static str)> {
+        let mut params = vec![("include[]", "user")];
+        if self.group {
+            params.push(("include[]", "group"));
+        }
+        if self.submission_history {
+            params.push(("include[]", "submission_history"));
+        }
+        params
+    }
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            fuzzy_threshold: DEFAULT_FUZZY_THRESHOLD,
+            mapping: HashMap::new(),
+            group: false,
+            include: SubmissionIncludes::default(),
+        }
+    }
+}
+
+/// Reads a mapping file of manual matches. It is a TOML file where each
+/// key is a booking name or email, and the value the Canvas login, e.g.
+///
+/// ```toml
+/// "Anna Berg" = "aberg@kth.se"
+/// "anna.berg@gmail.com" = "aberg@kth.se"
+/// ```
+pub fn read_mapping<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>, CanvasError> {
+    let content = std::fs::read_to_string(path.as_ref())?;
+    toml::from_str(&content).map_err(|e| CanvasError::Parse {
+        path: path.as_ref().to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+/// How downloaded files are arranged in the download folder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Layout {
+    /// Every file directly in the folder, as `<time>-<name>-<original>`.
+    #[default]
+    Flat,
+    /// One subfolder `<time>-<name>` per student, with the files inside.
+    PerStudent,
+}
+
+impl Layout {
+    /// The path of the file `name` belonging to the submission with the
+    /// subfolder `prefix`.
+    fn path(&self, folder: &Path, prefix: &str, name: &str) -> PathBuf {
+        match self {
+            Layout::Flat => folder.join(name),
+            Layout::PerStudent => folder.join(prefix).join(name),
+        }
+    }
+
+    /// The file name template used when none is given.
+    fn default_template(&self) -> FilenameTemplate {
+        match self {
+            Layout::Flat => FilenameTemplate::default(),
+            Layout::PerStudent => FilenameTemplate(vec![Part::Token(Token::Original)]),
+        }
+    }
+}
+
+/// A template for the names of downloaded files, e.g.
+/// `{kth_id}-{original}`. The tokens are `{time}` (of the booking),
+/// `{name}`, `{email}`, `{kth_id}`, `{original}` (the attachment name) and
+/// `{submission_id}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilenameTemplate(Vec<Part>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Token(Token),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Time,
+    Name,
+    Email,
+    KthId,
+    Original,
+    SubmissionId,
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        FilenameTemplate(vec![
+            Part::Token(Token::Time),
+            Part::Literal("-".to_string()),
+            Part::Token(Token::Name),
+            Part::Literal("-".to_string()),
+            Part::Token(Token::Original),
+        ])
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(format!("unmatched `}}` in `{}`", s));
+            }
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed `{{` in `{}`", s))?;
+            let token = match &rest[start + 1..start + end] {
+                "time" => Token::Time,
+                "name" => Token::Name,
+                "email" => Token::Email,
+                "kth_id" => Token::KthId,
+                "original" => Token::Original,
+                "submission_id" => Token::SubmissionId,
+                other => {
+                    return Err(format!(
+                        "unknown token `{{{}}}`, expected one of {{time}}, {{name}}, {{email}}, {{kth_id}}, {{original}} or {{submission_id}}",
+                        other
+                    ))
+                }
+            };
+            parts.push(Part::Token(token));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        if parts.is_empty() {
+            return Err("the template is empty".to_string());
+        }
+
+        Ok(FilenameTemplate(parts))
+    }
+}
+
+impl FilenameTemplate {
+    /// Whether the names differ between students, by their name, email,
+    /// KTH ID or submission ID. Without one, every student's `report.pdf`
+    /// gets the same name in a flat layout. `{time}` is not enough, since
+    /// several students can book the same slot.
+    pub fn identifies_student(&self) -> bool {
+        self.0.iter().any(|part| {
+            matches!(
+                part,
+                Part::Token(Token::Name | Token::Email | Token::KthId | Token::SubmissionId)
+            )
+        })
+    }
+
+    /// Renders the name of the file `original` of `submission`, booked at
+    /// `time`. The result is sanitized to be a valid file name.
+    fn render(&self, submission: &Submission, time: &str, original: &str) -> String {
+        let email = submission.user.email();
+        let rendered: String = self
+            .0
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.clone(),
+                Part::Token(Token::Time) => time.to_string(),
+                Part::Token(Token::Name) => submission.user.name.clone(),
+                Part::Token(Token::Email) => email.to_string(),
+                Part::Token(Token::KthId) => email.split('@').next().unwrap_or(email).to_string(),
+                Part::Token(Token::Original) => original.to_string(),
+                Part::Token(Token::SubmissionId) => submission.id.to_string(),
+            })
+            .collect();
+        sanitize_filename(&rendered)
+    }
+}
+
+/// Which attempts of a submission to download. Anything other than
+/// `Latest` needs the submission history, see `SubmissionIncludes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Attempt {
+    /// The current version of the submission.
+    #[default]
+    Latest,
+    /// Every attempt, named by attempt number and submission time.
+    All,
+    /// A specific attempt.
+    Number(u32),
+}
+
+impl FromStr for Attempt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(Attempt::Latest),
+            "all" => Ok(Attempt::All),
+            n => n
+                .parse()
+                .map(Attempt::Number)
+                .map_err(|_| format!("expected `latest`, `all` or an attempt number, got `{}`", n)),
+        }
+    }
+}
+
+/// Options controlling how submissions are written to disk.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// How files are arranged in the download folder.
+    pub layout: Layout,
+    /// How files are named, by default depending on the layout.
+    pub filename_template: Option<FilenameTemplate>,
+    /// Which attempts of each submission to download.
+    pub attempt: Attempt,
+    /// Skip attachments whose target file already exists, whatever its
+    /// size. Without it, only files of the size Canvas reports are skipped.
+    pub skip_existing: bool,
+    /// Download every file again, even if it is already present.
+    pub force: bool,
+    /// Only download attachments with these extensions, e.g. `pdf` or
+    /// `.zip`. All attachments are downloaded when empty.
+    pub only_ext: Vec<String>,
+    /// How many files of a submission to download at the same time.
+    pub attachment_concurrency: usize,
+    /// The time zone of the times in file names, `TIMEZONE` if not set.
+    pub timezone: Option<Tz>,
+    /// Extract downloaded zip archives into a folder next to them.
+    pub extract_zips: bool,
+    /// Delete zip archives once extracted, the folder is then recorded
+    /// as the downloaded file instead.
+    pub delete_extracted_zips: bool,
+}
+
+impl DownloadOptions {
+    /// Whether an attachment named `name` should be downloaded, by its
+    /// extension, ignoring case.
+    pub fn allows(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.only_ext.is_empty()
+            || self.only_ext.iter().any(|ext| {
+                let ext = ext.trim_start_matches('.').to_lowercase();
+                name.strip_suffix(&ext)
+                    .is_some_and(|stem| stem.ends_with('.'))
+            })
+    }
 }
 
 const GRADE_KEYS: [&str; 3] = ["pass_fail", "points", "letter_grade"];
 
 impl Canvas {
-    pub fn new(api_token: String) -> Self {
+    pub fn new(api_token: String) -> Result<Self, CanvasError> {
+        Self::with_base_url(api_token, DEFAULT_API_URL.to_string())
+    }
+
+    /// Creates a client talking to the Canvas API at `base_url`, e.g.
+    /// `https://canvas.kth.se/api/v1`.
+    pub fn with_base_url(api_token: String, base_url: String) -> Result<Self, CanvasError> {
+        let parsed = reqwest::Url::parse(&base_url).map_err(|e| CanvasError::InvalidUrl {
+            url: base_url.clone(),
+            reason: e.to_string(),
+        })?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(CanvasError::InvalidUrl {
+                url: base_url,
+                reason: "must be http or https".to_string(),
+            });
+        }
+        let base_url = base_url.trim_end_matches('/').to_string();
+
+        let mut authorization = HeaderValue::from_str(&format!("Bearer {}", api_token))
+            .map_err(|_| CanvasError::InvalidToken)?;
+        authorization.set_sensitive(true);
         let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", api_token)).unwrap(),
-        );
+        headers.insert(AUTHORIZATION, authorization);
 
         let client = reqwest::Client::builder()
             .default_headers(headers)
+            .user_agent(crate::USER_AGENT)
             .build()
             .unwrap();
 
-        Canvas { client }
+        Ok(Canvas {
+            client,
+            base_url,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_base_delay: DEFAULT_RETRY_BASE_DELAY,
+            timeout: DEFAULT_TIMEOUT,
+            fixtures: None,
+            download_progress: None,
+        })
     }
 
-    pub async fn get_courses(&self) -> Result<Vec<Course>, anyhow::Error> {
-        let mut courses: Vec<Course> = self
-            .get_paginated_data(&format!("{}/courses", API_URL))
-            .await?;
+    /// Sets how long to wait for Canvas before giving up on a request. For
+    /// attachment downloads this is the longest allowed pause between
+    /// received chunks, so that large files are not cut off.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
 
-        courses = courses
-            .into_iter()
-            .filter(|course: &Course| {
-                course
-                    .enrollments
-                    .iter()
-                    .any(|enrollment| enrollment.r#type != "student")
-            })
-            .collect();
+    /// Sets how many times a request is retried on 429 and 5xx responses,
+    /// and the initial delay of the exponential backoff between attempts.
+    pub fn with_retries(mut self, max_retries: u32, base_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_base_delay = base_delay;
+        self
+    }
+
+    /// Reads every response from JSON files in `dir` instead of asking
+    /// Canvas, e.g. `canvas/courses/1/assignments.json` for the assignments
+    /// of course 1. Attachment URLs are file paths relative to `dir`, and
+    /// grades are not sent anywhere.
+    pub fn with_fixtures(mut self, dir: PathBuf) -> Self {
+        self.fixtures = Some(dir);
+        self
+    }
+
+    /// Reports the progress of every attachment download to `progress`.
+    pub fn with_download_progress(mut self, progress: Arc<dyn DownloadProgress>) -> Self {
+        self.download_progress = Some(progress);
+        self
+    }
+
+    /// Checks that the API token is accepted by Canvas.
+    pub async fn verify_token(&self) -> Result<(), CanvasError> {
+        if self.fixtures.is_some() {
+            return Ok(());
+        }
+        self.send_with_retry(
+            self.client
+                .get(format!("{}/users/self", self.base_url))
+                .timeout(self.timeout),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Lists the courses where you have a non-student enrollment, newest
+    /// first. All pages are fetched before filtering and sorting.
+    pub async fn get_courses(&self) -> Result<Vec<Course>, CanvasError> {
+        let mut courses: Vec<Course> = self.get_paginated_data("/courses", &[]).await?;
+
+        courses.retain(|course: &Course| {
+            course
+                .enrollments
+                .iter()
+                .any(|enrollment| enrollment.r#type != "student")
+        });
 
-        courses.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        courses.sort_by_key(|c| std::cmp::Reverse(c.created_at));
 
         Ok(courses)
     }
 
-    pub async fn get_assignments(&self, course_id: &str) -> Result<Vec<Assignment>, anyhow::Error> {
+    /// Lists the published, gradable assignments of a course by due date.
+    /// All pages are fetched before filtering and sorting.
+    pub async fn get_assignments(
+        &self,
+        course_id: &str,
+        options: &AssignmentOptions,
+    ) -> Result<Vec<Assignment>, CanvasError> {
         let mut assignments: Vec<Assignment> = self
-            .get_paginated_data(&format!("{}/courses/{}/assignments", API_URL, course_id))
+            .get_paginated_data(&format!("/courses/{}/assignments", course_id), &[])
             .await?;
 
         assignments = assignments
             .into_iter()
-            .filter(|a| a.published)
-            .filter(|a| GRADE_KEYS.contains(&a.grading_type.as_str()))
+            .filter(|a| options.include_unpublished || a.published)
+            .filter(|a| options.all_grading_types || GRADE_KEYS.contains(&a.grading_type.as_str()))
             .collect();
 
         assignments.sort_by(|a, b| {
             a.due_at
-                .unwrap_or_else(|| Utc::now())
-                .cmp(&b.due_at.unwrap_or_else(|| Utc::now()))
+                .unwrap_or_else(Utc::now)
+                .cmp(&b.due_at.unwrap_or_else(Utc::now))
         });
 
         Ok(assignments)
     }
 
+    /// Fetches a single course, failing with `CourseNotFound` if it does
+    /// not exist.
+    pub async fn get_course(&self, course: &u32) -> Result<Course, CanvasError> {
+        self.get_resource(&format!("/courses/{}", course))
+            .await?
+            .ok_or(CanvasError::CourseNotFound(*course))
+    }
+
+    /// Fetches a single assignment, failing with `AssignmentNotFound` if it
+    /// does not exist.
+    pub async fn get_assignment(
+        &self,
+        course: &u32,
+        assignment: &u32,
+    ) -> Result<Assignment, CanvasError> {
+        self.get_resource(&format!("/courses/{}/assignments/{}", course, assignment))
+            .await?
+            .ok_or(CanvasError::AssignmentNotFound {
+                course: *course,
+                assignment: *assignment,
+            })
+    }
+
+    /// Lists the students enrolled in a course.
+    pub async fn get_students(&self, course: &u32) -> Result<Vec<User>, CanvasError> {
+        self.get_paginated_data(
+            &format!("/courses/{}/users", course),
+            &[("enrollment_type[]", "student"), ("include[]", "email")],
+        )
+        .await
+    }
+
     pub async fn get_assignment_submissions(
         &self,
         course: &u32,
         assignment: &u32,
         bookings: &[crate::remores::Booking],
-    ) -> Result<HashMap<Booking, Option<Submission>>, anyhow::Error> {
-        let mut submissions: Vec<Submission> = self
-            .get_paginated_data(&format!(
-                "{}/courses/{}/assignments/{}/submissions?include[]=user",
-                API_URL, course, assignment
-            ))
-            .await?;
+        options: &MatchOptions,
+    ) -> Result<HashMap<Booking, Option<Match>>, CanvasError> {
+        let mut include = options.include.clone();
+        include.group |= options.group;
 
-        let mut booking_map: HashMap<Booking, Option<Submission>> = bookings
-            .iter()
-            .map(|booking| (booking.clone(), None))
-            .collect();
+        let submissions = self.get_submissions(course, assignment, &include).await?;
+        Ok(match_submitted(bookings, &submissions, options).0)
+    }
 
-        for booking in bookings {
-            // Check if the booking kth email is in the submissions
-            if let Some(submission) = submissions
-                .iter()
-                .find(|submission| Email::KTHEmail(submission.user.email.clone()) == booking.email)
-            {
-                booking_map.insert(booking.clone(), Some(submission.clone()));
-            } else {
-                // If not, try to find a submission with a similar name,
-                // not perfect but better than nothing
-                submissions.sort_by(|a, b| {
-                    let a_sim = strsim::jaro(&a.user.name, &booking.name);
-                    let b_sim = strsim::jaro(&b.user.name, &booking.name);
-                    a_sim.partial_cmp(&b_sim).unwrap()
-                });
+    /// Lists all submissions of an assignment, with the `include` fields.
+    pub async fn get_submissions(
+        &self,
+        course: &u32,
+        assignment: &u32,
+        include: &SubmissionIncludes,
+    ) -> Result<Vec<Submission>, CanvasError> {
+        self.get_paginated_data(
+            &format!("/courses/{}/assignments/{}/submissions", course, assignment),
+            &include.params(),
+        )
+        .await
+    }
 
-                if let Some(submission) = submissions.pop() {
-                    if strsim::jaro(&submission.user.name, &booking.name) > 0.8 {
-                        booking_map.insert(booking.clone(), Some(submission));
-                    }
-                }
-            }
+    /// Sets the grade of a submission, optionally with a comment. `user_id`
+    /// is a Canvas user id, or e.g. `sis_login_id:<login>`.
+    pub async fn submit_grade(
+        &self,
+        course: &u32,
+        assignment: &u32,
+        user_id: &str,
+        grade: &str,
+        comment: Option<&str>,
+    ) -> Result<(), CanvasError> {
+        let mut form = vec![("submission[posted_grade]", grade)];
+        if let Some(comment) = comment {
+            form.push(("comment[text_comment]", comment));
         }
+        if self.fixtures.is_some() {
+            info!(user_id, ?form, "Not sending grade with fixtures");
+            return Ok(());
+        }
+
+        self.send_with_retry(
+            self.client
+                .put(format!(
+                    "{}/courses/{}/assignments/{}/submissions/{}",
+                    self.base_url, course, assignment, user_id
+                ))
+                .form(&form)
+                .timeout(self.timeout),
+        )
+        .await?;
 
-        Ok(booking_map)
+        Ok(())
     }
 
     pub async fn download_submission<T: AsRef<Path>>(
         &self,
         submission: &Submission,
         folder: T,
-        file_name: &str,
-    ) -> Result<Vec<PathBuf>, anyhow::Error> {
-        if submission.attachments.is_none() {
-            anyhow::bail!("No attachments found for submission");
+        time: &DateTime<Utc>,
+        options: &DownloadOptions,
+    ) -> Result<Vec<DownloadedFile>, CanvasError> {
+        let files = plan_files(submission, folder.as_ref(), time, options);
+        if files.is_empty() {
+            return Err(CanvasError::NoContent);
         }
 
-        let mut paths = vec![];
-        for attachment in submission.attachments.as_ref().unwrap_or(&vec![]) {
-            let file_name = format!("{}-{}", file_name, attachment.display_name);
-            let path = PathBuf::from(folder.as_ref()).join(file_name);
-            paths.push(path.clone());
+        let results: Vec<Result<DownloadedFile, CanvasError>> = stream::iter(files)
+            .filter(|(_, source)| std::future::ready(source.allowed(options)))
+            .map(|(path, source)| self.download_file(path, source, options))
+            .buffer_unordered(options.attachment_concurrency.max(1))
+            .collect()
+            .await;
 
-            let mut file = File::create(path)?;
-            let resp = self.client.get(&attachment.url).send().await?;
-            let bytes = resp.bytes().await?;
+        let mut downloaded = vec![];
+        let mut errors = vec![];
+        for result in results {
+            match result {
+                Ok(file) => downloaded.push(file),
+                Err(e) => errors.push(e),
+            }
+        }
+        // The files finish in any order.
+        downloaded.sort_by(|a, b| a.path.cmp(&b.path));
+
+        match errors.len() {
+            0 => Ok(downloaded),
+            1 if downloaded.is_empty() => Err(errors.remove(0)),
+            _ => Err(CanvasError::FilesFailed {
+                files: downloaded,
+                errors,
+            }),
+        }
+    }
+
+    /// Writes one file of a submission to `path`.
+    async fn download_file(
+        &self,
+        path: PathBuf,
+        source: Source<'_>,
+        options: &DownloadOptions,
+    ) -> Result<DownloadedFile, CanvasError> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
 
-            file.write_all(&bytes)?;
+        if let Some(bytes) = source.present(&path, options) {
+            debug!("Skipping {}, already present", path.display());
+            return Ok(DownloadedFile {
+                path,
+                bytes,
+                skipped: true,
+            });
+        }
+
+        let bytes = match source {
+            Source::Attachment(attachment) => self
+                .download_fresh_attachment(attachment, &path)
+                .await
+                .map_err(|e| CanvasError::Download {
+                    name: attachment.display_name.clone(),
+                    source: Box::new(e),
+                })?,
+            Source::Inline(content) => {
+                let part_path = part_path(&path);
+                let result = async {
+                    tokio::fs::write(&part_path, &content).await?;
+                    tokio::fs::rename(&part_path, &path).await
+                }
+                .await;
+                if result.is_err() {
+                    let _ = tokio::fs::remove_file(&part_path).await;
+                }
+                result?;
+                content.len() as u64
+            }
+        };
+
+        let is_zip = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+        if options.extract_zips && is_zip {
+            let archive = path.clone();
+            let extracted =
+                tokio::task::spawn_blocking(move || extract_zip(&archive, MAX_EXTRACTED_BYTES))
+                    .await
+                    .map_err(std::io::Error::from)?;
+            // The archive itself was downloaded fine, so a broken one is
+            // kept as it is rather than failing the download.
+            match extracted {
+                Ok(dir) => {
+                    info!("Extracted {} to {}", path.display(), dir.display());
+                    if options.delete_extracted_zips {
+                        tokio::fs::remove_file(&path).await?;
+                        return Ok(DownloadedFile {
+                            path: dir,
+                            bytes,
+                            skipped: false,
+                        });
+                    }
+                }
+                Err(e) => warn!("Keeping {} without extracting it: {}", path.display(), e),
+            }
         }
 
-        Ok(paths)
+        Ok(DownloadedFile {
+            path,
+            bytes,
+            skipped: false,
+        })
     }
 
-    async fn get_paginated_data<T: for<'de> Deserialize<'de>>(
+    /// The files `download_submission` would write or skip, without
+    /// downloading anything. The size of a file to download is the one
+    /// Canvas reports, if any.
+    pub fn planned_files<T: AsRef<Path>>(
         &self,
-        url: &str,
-    ) -> Result<Vec<T>, anyhow::Error> {
-        let mut data = vec![];
+        submission: &Submission,
+        folder: T,
+        time: &DateTime<Utc>,
+        options: &DownloadOptions,
+    ) -> Vec<DownloadedFile> {
+        plan_files(submission, folder.as_ref(), time, options)
+            .into_iter()
+            .filter(|(_, source)| source.allowed(options))
+            .map(|(path, source)| {
+                let present = source.present(&path, options);
+                DownloadedFile {
+                    bytes: present.or(source.size()).unwrap_or_default(),
+                    skipped: present.is_some(),
+                    path,
+                }
+            })
+            .collect()
+    }
 
-        let mut url = url.to_string();
+    /// How many attachments of `submission` are not downloaded because of
+    /// `options.only_ext`.
+    pub fn excluded_count(&self, submission: &Submission, options: &DownloadOptions) -> usize {
+        plan_files(submission, Path::new(""), &Utc::now(), options)
+            .iter()
+            .filter(|(_, source)| !source.allowed(options))
+            .count()
+    }
 
-        loop {
-            let resp = self
-                .client
-                .get(&url)
-                .query(&[("per_page", 100)])
-                .send()
-                .await?;
-            let headers = resp.headers().clone();
+    /// Downloads several submissions concurrently, with at most
+    /// `concurrency` in flight at once. Each submission is paired with the
+    /// time of its booking, and results are yielded as they finish.
+    pub fn download_submissions<'a>(
+        &'a self,
+        downloads: Vec<(Submission, DateTime<Utc>)>,
+        folder: &'a Path,
+        options: &'a DownloadOptions,
+        concurrency: usize,
+    ) -> impl Stream<Item = (Submission, Result<Vec<DownloadedFile>, CanvasError>)> + 'a {
+        stream::iter(downloads)
+            .map(move |(submission, time)| async move {
+                let result = self
+                    .download_submission(&submission, folder, &time, options)
+                    .await;
+                (submission, result)
+            })
+            .buffer_unordered(concurrency)
+    }
 
-            data.extend(resp.json::<Vec<T>>().await?);
+    /// Downloads an attachment, and if its signed URL has expired, e.g.
+    /// because it waited long in the queue, fetches a fresh URL and tries
+    /// once more. Returns the number of bytes written.
+    async fn download_fresh_attachment(
+        &self,
+        attachment: &Attachment,
+        path: &Path,
+    ) -> Result<u64, CanvasError> {
+        match self.download_attachment(attachment, path).await {
+            Err(CanvasError::ExpiredUrl) => {
+                let Some(id) = attachment.id else {
+                    return Err(CanvasError::ExpiredUrl);
+                };
+                info!(
+                    id,
+                    "Download link of {} expired, refreshing", attachment.display_name
+                );
+                let fresh: Attachment = self
+                    .get_resource(&format!("/files/{}", id))
+                    .await?
+                    .ok_or(CanvasError::ExpiredUrl)?;
+                self.download_attachment(&fresh, path).await
+            }
+            result => result,
+        }
+    }
+
+    async fn download_attachment(
+        &self,
+        attachment: &Attachment,
+        path: &Path,
+    ) -> Result<u64, CanvasError> {
+        if let Some(dir) = &self.fixtures {
+            let part_path = part_path(path);
+            let result = async {
+                let bytes = tokio::fs::copy(dir.join(&attachment.url), &part_path).await?;
+                tokio::fs::rename(&part_path, path).await?;
+                Ok(bytes)
+            }
+            .await;
+            if result.is_err() {
+                let _ = tokio::fs::remove_file(&part_path).await;
+            }
+            return result;
+        }
+
+        let resp = match self.send_with_retry(self.client.get(&attachment.url)).await {
+            Err(CanvasError::Http(e)) if e.status() == Some(StatusCode::FORBIDDEN) => {
+                return Err(CanvasError::ExpiredUrl);
+            }
+            result => result?,
+        };
+
+        // An expired S3 link can also come back as an XML error document,
+        // which should not be saved as the file.
+        let is_xml = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|media_type| {
+                let media_type = media_type.trim();
+                media_type.eq_ignore_ascii_case("application/xml")
+                    || media_type.eq_ignore_ascii_case("text/xml")
+            });
+        let xml_file = Path::new(&attachment.display_name)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("xml"));
+        if is_xml && !xml_file {
+            return Err(CanvasError::ExpiredUrl);
+        }
+
+        // Write to a temporary file first, so that an interrupted download
+        // never leaves a truncated file under the final name.
+        let part_path = part_path(path);
 
-            if let Some(link) = headers.get("link") {
-                let link = link
-                    .to_str()?
-                    .split(",")
-                    .find(|link| link.ends_with("rel=\"next\""))
-                    .map(|link| {
-                        link.trim_start_matches("<")
-                            .split_once(">")
-                            .map(|(link, _)| link)
-                    })
-                    .flatten();
-
-                if let Some(link) = link {
-                    url = link.to_string();
-                } else {
-                    break;
+        let expected_len = resp.content_length();
+        let mut file = File::create(&part_path).await?;
+        let progress = self.download_progress.as_deref();
+        if let Some(progress) = progress {
+            progress.start(path, expected_len);
+        }
+        let result = async {
+            let mut written: u64 = 0;
+            let mut stream = resp.bytes_stream();
+            while let Some(chunk) = tokio::time::timeout(self.timeout, stream.next())
+                .await
+                .map_err(|_| CanvasError::Timeout(self.timeout))?
+            {
+                let chunk = chunk?;
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+                if let Some(progress) = progress {
+                    progress.advance(path, chunk.len() as u64);
+                }
+            }
+            file.flush().await?;
+
+            if let Some(expected_len) = expected_len {
+                if written != expected_len {
+                    return Err(CanvasError::Incomplete {
+                        expected: expected_len,
+                        received: written,
+                    });
                 }
             }
+
+            drop(file);
+            tokio::fs::rename(&part_path, path).await?;
+            Ok(written)
         }
+        .await;
 
-        Ok(data)
+        if let Some(progress) = progress {
+            progress.finish(path);
+        }
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&part_path).await;
+        }
+
+        result
+    }
+
+    /// Fetches a single resource, or `None` if Canvas responds 404.
+    async fn get_resource<T: for<'de> Deserialize<'de>>(
+        &self,
+        path: &str,
+    ) -> Result<Option<T>, CanvasError> {
+        if let Some(dir) = &self.fixtures {
+            return read_fixture(dir, path).await;
+        }
+
+        let request = self
+            .client
+            .get(format!("{}{}", self.base_url, path))
+            .timeout(self.timeout);
+        match self.send_with_retry(request).await {
+            Ok(resp) => Ok(Some(resp.json().await?)),
+            Err(CanvasError::Http(e)) if e.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fetches every page of a listing. `query` is only added to the first
+    /// request, since Canvas keeps it in the links to the following pages.
+    #[tracing::instrument(skip(self))]
+    async fn get_paginated_data<T: for<'de> Deserialize<'de>>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Vec<T>, CanvasError> {
+        if let Some(dir) = &self.fixtures {
+            return Ok(read_fixture(dir, path).await?.unwrap_or_default());
+        }
+
+        let mut data = vec![];
+
+        let mut url = format!("{}{}", self.base_url, path);
+        let mut query = query;
+        let mut pages = 0;
+
+        loop {
+            debug!(url, "Fetching page");
+            let resp = self
+                .send_with_retry(
+                    self.client
+                        .get(&url)
+                        .query(&[("per_page", 100)])
+                        .query(query)
+                        .timeout(self.timeout),
+                )
+                .await?;
+            query = &[];
+            let headers = resp.headers().clone();
+
+            data.extend(resp.json::<Vec<T>>().await?);
+            pages += 1;
+
+            match next_page(&headers) {
+                Some(link) => url = link,
+                None => break,
+            }
+        }
+
+        info!(pages, items = data.len(), "Fetched all pages");
+        Ok(data)
+    }
+
+    /// The Canvas page where API tokens are generated.
+    fn settings_url(&self) -> String {
+        format!(
+            "{}/profile/settings",
+            self.base_url.trim_end_matches("/api/v1")
+        )
+    }
+
+    /// Sends `request`, retrying with exponential backoff and jitter on
+    /// rate limiting (429), server errors (5xx), timeouts and failed
+    /// connections.
+    async fn send_with_retry(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, CanvasError> {
+        let mut attempt = 0;
+
+        loop {
+            // Only requests with streaming bodies can't be cloned, and those
+            // are never sent to Canvas.
+            let request = request.try_clone().expect("request body is not a stream");
+            let resp = match tokio::time::timeout(self.timeout, request.send()).await {
+                Ok(Ok(resp)) => resp,
+                Ok(Err(e)) if !(e.is_connect() || e.is_timeout()) => return Err(e.into()),
+                result => {
+                    let error = match result {
+                        Ok(Err(e)) => CanvasError::Http(e),
+                        _ => CanvasError::Timeout(self.timeout),
+                    };
+                    if attempt >= self.max_retries {
+                        return Err(error);
+                    }
+                    let delay = self.backoff(attempt);
+                    warn!(%error, ?delay, "Retrying Canvas request");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    continue;
+                }
+            };
+
+            let status = resp.status();
+            debug!(url = %resp.url(), %status, "Canvas responded");
+            if status == StatusCode::UNAUTHORIZED {
+                return Err(CanvasError::Unauthorized {
+                    settings_url: self.settings_url(),
+                });
+            }
+            // Canvas answers 403 when the rate limit budget is used up.
+            let remaining = rate_limit_remaining(resp.headers());
+            let throttled = status == StatusCode::FORBIDDEN && remaining.is_some_and(|r| r <= 0.0);
+            if status.as_u16() != 429 && !status.is_server_error() && !throttled {
+                if let Some(delay) = remaining.and_then(throttle_delay) {
+                    warn!(
+                        remaining,
+                        ?delay,
+                        "Canvas rate limit is running low, slowing down"
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                return Ok(resp.error_for_status()?);
+            }
+
+            if attempt >= self.max_retries {
+                return Err(CanvasError::RateLimited {
+                    url: resp.url().to_string(),
+                    status,
+                    retries: attempt,
+                });
+            }
+
+            let delay = retry_after(resp.headers()).unwrap_or_else(|| self.backoff(attempt));
+            warn!(url = %resp.url(), %status, ?delay, "Retrying Canvas request");
+            tokio::time::sleep(delay).await;
+
+            attempt += 1;
+        }
+    }
+
+    /// How long to wait before retry number `attempt`, doubling each time,
+    /// with up to 50% jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .retry_base_delay
+            .saturating_mul(2u32.saturating_pow(attempt));
+        delay + delay.mul_f64(rand::random::<f64>() * 0.5)
+    }
+}
+
+/// Pairs each booking with its submission, see `match_submissions`. For
+/// group assignments, a booking gets the files of their group.
+pub fn match_bookings(
+    bookings: &[Booking],
+    submissions: &[Submission],
+    options: &MatchOptions,
+) -> HashMap<Booking, Option<Match>> {
+    let mut booking_map = match_submissions(bookings, submissions, options);
+    if options.group {
+        use_group_submissions(&mut booking_map, submissions);
+    }
+    booking_map
+}
+
+/// Like `match_bookings`, but only bookings of students who have submitted
+/// are matched. The other bookings matched to the `unsubmitted` placeholder
+/// of a student are returned separately, with the student. For group
+/// assignments, a member who didn't upload the files is matched too, and
+/// only counts as not submitted if nobody in their group has submitted.
+pub fn match_submitted(
+    bookings: &[Booking],
+    submissions: &[Submission],
+    options: &MatchOptions,
+) -> (HashMap<Booking, Option<Match>>, HashMap<Booking, User>) {
+    let mut not_submitted = HashMap::new();
+    if options.group {
+        let mut booking_map = match_bookings(bookings, submissions, options);
+        for (booking, m) in booking_map.iter_mut() {
+            if m.as_ref()
+                .is_some_and(|m| !m.submission.is_submitted() && !m.submission.has_content())
+            {
+                let placeholder = m.take().unwrap().submission;
+                not_submitted.insert(booking.clone(), placeholder.user);
+            }
+        }
+        return (booking_map, not_submitted);
+    }
+
+    let (submitted, placeholders): (Vec<_>, Vec<_>) = submissions
+        .iter()
+        .cloned()
+        .partition(Submission::is_submitted);
+    let booking_map = match_bookings(bookings, &submitted, options);
+
+    // Bookings without a submission are matched to the empty placeholders
+    // too, to tell a student who hasn't submitted from one who wasn't found.
+    let unmatched: Vec<Booking> = booking_map
+        .iter()
+        .filter(|(_, m)| m.is_none())
+        .map(|(booking, _)| booking.clone())
+        .collect();
+    for (booking, m) in match_bookings(&unmatched, &placeholders, options) {
+        if let Some(placeholder) = m {
+            not_submitted.insert(booking, placeholder.submission.user);
+        }
+    }
+    (booking_map, not_submitted)
+}
+
+/// The `submissions` sorted by how similar the student name is to `name`,
+/// most similar first, with the similarity.
+pub fn by_name_similarity<'a>(
+    name: &str,
+    submissions: impl IntoIterator<Item = &'a Submission>,
+) -> Vec<(&'a Submission, f64)> {
+    let mut candidates: Vec<(&Submission, f64)> = submissions
+        .into_iter()
+        .map(|submission| (submission, name_similarity(name, &submission.user.name)))
+        .collect();
+    candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    candidates
+}
+
+/// Pairs each booking with its submission. Bookings listed in the manual
+/// mapping are matched first, then bookings are matched by KTH email or by
+/// the local part of the email, and the remaining ones by the most similar
+/// student name, most similar pair first. A student who booked several
+/// times gets their submission matched to each booking by mapping or
+/// email, but a fuzzy match never reuses a submission.
+fn match_submissions(
+    bookings: &[Booking],
+    submissions: &[Submission],
+    options: &MatchOptions,
+) -> HashMap<Booking, Option<Match>> {
+    let mut booking_map: HashMap<Booking, Option<Match>> = bookings
+        .iter()
+        .map(|booking| (booking.clone(), None))
+        .collect();
+    let mut assigned: HashSet<u64> = HashSet::new();
+    let mut mapped: HashSet<&Booking> = HashSet::new();
+
+    for booking in bookings {
+        let Some(login) = options
+            .mapping
+            .get(&booking.name)
+            .or_else(|| options.mapping.get(&booking.email.to_string()))
+        else {
+            continue;
+        };
+        mapped.insert(booking);
+
+        if let Some(submission) = submissions
+            .iter()
+            .find(|submission| submission.user.identifier().eq_ignore_ascii_case(login))
+        {
+            assigned.insert(submission.id);
+            booking_map.insert(
+                booking.clone(),
+                Some(Match {
+                    submission: submission.clone(),
+                    method: MatchMethod::Manual,
+                }),
+            );
+        }
+    }
+
+    for booking in bookings.iter().filter(|b| !mapped.contains(b)) {
+        // Check if the booking kth email is in the submissions
+        if let Some(submission) = submissions
+            .iter()
+            .find(|submission| booking.email.is_kth_login(submission.user.identifier()))
+        {
+            assigned.insert(submission.id);
+            booking_map.insert(
+                booking.clone(),
+                Some(Match {
+                    submission: submission.clone(),
+                    method: MatchMethod::Email,
+                }),
+            );
+        }
+    }
+
+    for booking in bookings.iter().filter(|b| !mapped.contains(b)) {
+        if booking_map[booking].is_some() {
+            continue;
+        }
+
+        // Then for a login with the same local part, which catches other
+        // KTH domains and emails with the KTH ID
+        let local_part = booking.email.local_part();
+        if let Some(submission) = submissions.iter().find(|submission| {
+            !local_part.is_empty()
+                && !assigned.contains(&submission.id)
+                && submission
+                    .user
+                    .email()
+                    .split_once('@')
+                    .is_some_and(|(login, _)| login.eq_ignore_ascii_case(local_part))
+        }) {
+            assigned.insert(submission.id);
+            booking_map.insert(
+                booking.clone(),
+                Some(Match {
+                    submission: submission.clone(),
+                    method: MatchMethod::LocalPart,
+                }),
+            );
+        }
+    }
+
+    // If not, try to find a submission with a similar name, not perfect
+    // but better than nothing. The most similar pairs are matched first,
+    // so that a submission goes to the booking with the closest name
+    // rather than to whichever booking comes first.
+    let candidates: Vec<(&Booking, Vec<(&Submission, f64)>)> = bookings
+        .iter()
+        .filter(|booking| !mapped.contains(booking) && booking_map[*booking].is_none())
+        .map(|booking| {
+            let available = submissions
+                .iter()
+                .filter(|submission| !assigned.contains(&submission.id));
+            (booking, by_name_similarity(&booking.name, available))
+        })
+        .collect();
+
+    let mut pairs: Vec<(usize, &Submission, f64)> = candidates
+        .iter()
+        .enumerate()
+        .flat_map(|(i, (_, similar))| {
+            similar
+                .iter()
+                .filter(|(_, score)| *score > options.fuzzy_threshold)
+                .map(move |&(submission, score)| (i, submission, score))
+        })
+        .collect();
+    pairs.sort_by(|(_, _, a), (_, _, b)| b.total_cmp(a));
+
+    let mut matched: HashSet<usize> = HashSet::new();
+    for (i, submission, score) in pairs {
+        if matched.contains(&i) || assigned.contains(&submission.id) {
+            continue;
+        }
+        let (booking, similar) = &candidates[i];
+        let ambiguous = similar.iter().any(|(other, other_score)| {
+            other.id != submission.id
+                && *other_score <= score
+                && score - other_score < AMBIGUITY_DELTA
+        });
+
+        matched.insert(i);
+        assigned.insert(submission.id);
+        booking_map.insert(
+            (*booking).clone(),
+            Some(Match {
+                submission: submission.clone(),
+                method: MatchMethod::Fuzzy { score, ambiguous },
+            }),
+        );
+    }
+
+    booking_map
+}
+
+/// For group assignments, only the member who uploaded has the files on
+/// their submission. Gives every matched member without files the files
+/// submitted by someone in their group.
+fn use_group_submissions(
+    booking_map: &mut HashMap<Booking, Option<Match>>,
+    submissions: &[Submission],
+) {
+    for m in booking_map.values_mut().flatten() {
+        if m.submission.has_content() {
+            continue;
+        }
+        let Some(group_id) = m.submission.group.as_ref().and_then(|g| g.id) else {
+            continue;
+        };
+
+        if let Some(uploaded) = submissions
+            .iter()
+            .find(|s| s.has_content() && s.group.as_ref().and_then(|g| g.id) == Some(group_id))
+        {
+            m.submission.attachments = uploaded.attachments.clone();
+            m.submission.body = uploaded.body.clone();
+            m.submission.url = uploaded.url.clone();
+            m.submission.submission_history = uploaded.submission_history.clone();
+        }
+    }
+}
+
+/// Makes `name` safe to use as a single path component, by replacing path
+/// separators, characters reserved on Windows and control characters with
+/// `_`, collapsing whitespace, and removing trailing dots.
+pub fn sanitize_filename(name: &str) -> String {
+    let replaced: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let collapsed = replaced.split_whitespace().collect::<Vec<_>>().join(" ");
+    let trimmed = collapsed.trim_end_matches(['.', ' ']);
+
+    if trimmed.is_empty() {
+        "_".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// A file written by `download_submission`.
+#[derive(Debug, Clone)]
+pub struct DownloadedFile {
+    pub path: PathBuf,
+    /// The size of the file.
+    pub bytes: u64,
+    /// The file already existed, and was not downloaded again.
+    pub skipped: bool,
+}
+
+/// Extracts the zip archive at `path` into a folder next to it named after
+/// the archive, e.g. `lab1.zip` into `lab1/`, and returns the folder.
+/// Entries that would end up outside the folder, like `../evil`, are
+/// skipped, and extraction stops with an error after `max_bytes`.
+fn extract_zip(path: &Path, max_bytes: u64) -> Result<PathBuf, CanvasError> {
+    let invalid = |e: zip::result::ZipError| CanvasError::Extract {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    };
+    let dir = path.with_extension("");
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?).map_err(invalid)?;
+
+    let mut remaining = max_bytes;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(invalid)?;
+        let Some(name) = entry.enclosed_name() else {
+            warn!(
+                "Skipping {} in {}, it would be extracted outside {}",
+                String::from_utf8_lossy(entry.name_raw()),
+                path.display(),
+                dir.display()
+            );
+            continue;
+        };
+        let target = dir.join(name);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // The sizes in the archive can lie, so count what is written.
+        let written = std::io::copy(
+            &mut std::io::Read::take(&mut entry, remaining + 1),
+            &mut std::fs::File::create(&target)?,
+        )?;
+        if written > remaining {
+            std::fs::remove_file(&target)?;
+            return Err(CanvasError::Extract {
+                path: path.to_path_buf(),
+                reason: format!("it unpacks to more than {} bytes", max_bytes),
+            });
+        }
+        remaining -= written;
+    }
+    Ok(dir)
+}
+
+/// The temporary file that a file is written to before it is renamed to
+/// `path`, next to it so that the rename is atomic.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    PathBuf::from(part_path)
+}
+
+/// Where the content of a downloaded file comes from.
+enum Source<'a> {
+    Attachment(&'a Attachment),
+    /// The submitted text or link of a submission without attachments,
+    /// e.g. an `online_text_entry` or `online_url` one.
+    Inline(String),
+}
+
+impl Source<'_> {
+    /// Whether the file is downloaded with `options.only_ext`. The text or
+    /// link of a submission always is.
+    fn allowed(&self, options: &DownloadOptions) -> bool {
+        match self {
+            Source::Attachment(attachment) => options.allows(&attachment.display_name),
+            Source::Inline(_) => true,
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        match self {
+            Source::Attachment(attachment) => attachment.size,
+            Source::Inline(content) => Some(content.len() as u64),
+        }
+    }
+
+    /// The size of the file at `path` if it is already a complete copy and
+    /// should not be downloaded again. Files are only ever created by
+    /// renaming a fully written `.part` file, so with `skip_existing` any
+    /// existing file is, and otherwise one of the expected size.
+    fn present(&self, path: &Path, options: &DownloadOptions) -> Option<u64> {
+        if options.force {
+            return None;
+        }
+        let bytes = std::fs::metadata(path).ok()?.len();
+        (options.skip_existing || self.size() == Some(bytes)).then_some(bytes)
+    }
+}
+
+/// Decides which files to write for a submission booked at `time`, and
+/// where.
+fn plan_files<'a>(
+    submission: &'a Submission,
+    folder: &Path,
+    time: &DateTime<Utc>,
+    options: &DownloadOptions,
+) -> Vec<(PathBuf, Source<'a>)> {
+    let current = Content {
+        attachments: submission.attachments.as_deref().unwrap_or_default(),
+        body: submission.body.as_deref(),
+        url: submission.url.as_deref(),
+    };
+    let history = || submission.submission_history.iter().flatten();
+    let timezone = options.timezone.unwrap_or(TIMEZONE);
+
+    let versions: Vec<(Option<String>, Content)> = match options.attempt {
+        Attempt::Latest => vec![(None, current)],
+        Attempt::Number(n) => history()
+            .filter(|version| version.attempt == Some(n))
+            .map(|version| (None, version.content()))
+            .collect(),
+        Attempt::All if submission.submission_history.is_none() => vec![(None, current)],
+        Attempt::All => history()
+            .map(|version| {
+                let submitted_at = version
+                    .submitted_at
+                    .map(|t| t.with_timezone(&timezone).format("%Y%m%d%H%M").to_string())
+                    .unwrap_or_default();
+                let label = format!(
+                    "attempt{}-{}",
+                    version.attempt.unwrap_or_default(),
+                    submitted_at
+                );
+                (Some(label), version.content())
+            })
+            .collect(),
+    };
+
+    let (names, sources): (Vec<String>, Vec<Source>) = versions
+        .into_iter()
+        .flat_map(|(label, content)| content.files().into_iter().map(move |f| (label.clone(), f)))
+        .map(|(label, (name, source))| match label {
+            Some(label) => (format!("{}-{}", label, name), source),
+            None => (name, source),
+        })
+        .unzip();
+
+    let time = time
+        .with_timezone(&timezone)
+        .format("%Y%m%d%H%M")
+        .to_string();
+    let prefix = format!("{}-{}", time, sanitize_filename(&submission.user.name));
+    let template = options
+        .filename_template
+        .clone()
+        .unwrap_or_else(|| options.layout.default_template());
+
+    // A template without `{original}` gives every file the same name.
+    let names = unique_names(
+        names
+            .iter()
+            .map(|name| template.render(submission, &time, name))
+            .collect(),
+    );
+
+    names
+        .into_iter()
+        .map(|name| options.layout.path(folder, &prefix, &name))
+        .zip(sources)
+        .collect()
+}
+
+/// The submitted files, text or link of one version of a submission.
+struct Content<'a> {
+    attachments: &'a [Attachment],
+    body: Option<&'a str>,
+    url: Option<&'a str>,
+}
+
+impl<'a> Content<'a> {
+    /// The files to write, with their unique, sanitized names.
+    fn files(&self) -> Vec<(String, Source<'a>)> {
+        if self.attachments.is_empty() {
+            let mut files = vec![];
+            if let Some(body) = self.body.filter(|b| !b.trim().is_empty()) {
+                files.push(("text.html".to_string(), Source::Inline(body.to_string())));
+            }
+            if let Some(url) = self.url.filter(|u| !u.trim().is_empty()) {
+                files.push(("url.txt".to_string(), Source::Inline(format!("{}\n", url))));
+            }
+            return files;
+        }
+
+        let names = unique_names(
+            self.attachments
+                .iter()
+                .map(|attachment| sanitize_filename(&attachment.display_name))
+                .collect(),
+        );
+
+        names
+            .into_iter()
+            .zip(self.attachments.iter().map(Source::Attachment))
+            .collect()
+    }
+}
+
+/// Makes file names unique by numbering the ones that occur more than once,
+/// e.g. two `main.rs` become `main-1.rs` and `main-2.rs`.
+fn unique_names(names: Vec<String>) -> Vec<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for name in &names {
+        *counts.entry(name).or_default() += 1;
+    }
+
+    let mut used: HashSet<String> = names
+        .iter()
+        .filter(|name| counts[name.as_str()] == 1)
+        .cloned()
+        .collect();
+
+    names
+        .iter()
+        .map(|name| {
+            if counts[name.as_str()] == 1 {
+                return name.clone();
+            }
+
+            let path = Path::new(name);
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = path
+                .extension()
+                .map(|e| format!(".{}", e.to_string_lossy()))
+                .unwrap_or_default();
+
+            (1..)
+                .map(|i| format!("{}-{}{}", stem, i, extension))
+                .find(|candidate| used.insert(candidate.clone()))
+                .unwrap()
+        })
+        .collect()
+}
+
+/// How similar the booking name `booked` is to the student name, between
+/// 0.0 and 1.0. Either system may put the family name first, so names like
+/// `Berg, Anna` are read as `Anna Berg`, and `booked` is also compared with
+/// its words reversed.
+fn name_similarity(booked: &str, student: &str) -> f64 {
+    let booked = uncomma_name(&normalize_name(booked));
+    let student = uncomma_name(&normalize_name(student));
+    let reversed = booked
+        .split_whitespace()
+        .rev()
+        .collect::<Vec<_>>()
+        .join(" ");
+    strsim::jaro(&student, &booked).max(strsim::jaro(&student, &reversed))
+}
+
+/// Turns `last, first` into `first last`, with single spaces between the
+/// words.
+fn uncomma_name(name: &str) -> String {
+    let name = match name.split_once(',') {
+        Some((last, first)) => format!("{} {}", first, last),
+        None => name.to_string(),
+    };
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Lowercases a name and strips diacritics, so that e.g. "Åsa Lindén" and
+/// "asa linden" compare as equal.
+fn normalize_name(name: &str) -> String {
+    name.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Reads the fixture for the API path `path` in `dir`, or `None` if there
+/// is none.
+async fn read_fixture<T: for<'de> Deserialize<'de>>(
+    dir: &Path,
+    path: &str,
+) -> Result<Option<T>, CanvasError> {
+    let file = dir.join(format!("canvas{}.json", path));
+    debug!(file = %file.display(), "Reading fixture");
+    let content = match tokio::fs::read_to_string(&file).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_str(&content)
+        .map(Some)
+        .map_err(|e| CanvasError::Fixture {
+            path: file,
+            reason: e.to_string(),
+        })
+}
+
+/// Finds the URL of the next page in a Canvas `Link` header, if there is one.
+fn next_page(headers: &HeaderMap) -> Option<String> {
+    let header = headers.get(LINK)?.to_str().ok()?;
+    let next = header
+        .split(',')
+        .map(|link| link.trim())
+        .find(|link| link.ends_with("rel=\"next\""))
+        .and_then(|link| {
+            link.trim_start_matches('<')
+                .split_once('>')
+                .map(|(link, _)| link.to_string())
+        });
+    debug!(link = header, ?next, "Parsed Link header");
+    next
+}
+
+/// The remaining Canvas rate limit budget, from the
+/// `X-Rate-Limit-Remaining` header.
+fn rate_limit_remaining(headers: &HeaderMap) -> Option<f64> {
+    headers
+        .get("x-rate-limit-remaining")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// How long to wait before the next request with `remaining` rate limit
+/// budget, growing as it approaches zero. `None` when there's plenty left.
+fn throttle_delay(remaining: f64) -> Option<Duration> {
+    if remaining >= RATE_LIMIT_THRESHOLD {
+        return None;
+    }
+    let used = 1.0 - remaining.max(0.0) / RATE_LIMIT_THRESHOLD;
+    Some(MAX_THROTTLE_DELAY.mul_f64(used))
+}
+
+/// Parses a `Retry-After` header, given either as a number of seconds or
+/// as an HTTP date, capped at `MAX_RETRY_AFTER`.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            (date.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remores::Email;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn sanitize_filename_replaces_separators_and_reserved_characters() {
+        assert_eq!(sanitize_filename("lab/1\\report.pdf"), "lab_1_report.pdf");
+        assert_eq!(sanitize_filename("12:30 notes.txt"), "12_30 notes.txt");
+        assert_eq!(sanitize_filename("a*b?c\"d<e>f|g"), "a_b_c_d_e_f_g");
+        assert_eq!(sanitize_filename("tab\there"), "tab_here");
+    }
+
+    #[test]
+    fn sanitize_filename_removes_trailing_dots() {
+        assert_eq!(sanitize_filename("notes..."), "notes");
+        assert_eq!(sanitize_filename("notes. . "), "notes");
+        assert_eq!(sanitize_filename("..."), "_");
+    }
+
+    #[test]
+    fn sanitize_filename_keeps_the_extension() {
+        assert_eq!(sanitize_filename("Lab 1: Report.pdf"), "Lab 1_ Report.pdf");
+        assert_eq!(sanitize_filename("archive.tar.gz"), "archive.tar.gz");
+    }
+
+    fn submission(id: u64, name: &str, login: &str, files: &[&str]) -> Submission {
+        let attachments: Vec<_> = files
+            .iter()
+            .map(|file| serde_json::json!({ "url": file, "display_name": file }))
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "attachments": attachments,
+            "workflow_state": "submitted",
+            "user": { "name": name, "login_id": login },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn sanitize_filename_keeps_names_in_one_path_component() {
+        assert_eq!(sanitize_filename("Anna/Björk"), "Anna_Björk");
+        assert_eq!(sanitize_filename("../../evil.sh"), ".._.._evil.sh");
+        assert_eq!(sanitize_filename(".."), "_");
+    }
+
+    #[test]
+    fn planned_files_stay_in_the_folder() {
+        let folder = Path::new("downloads");
+        let submission = submission(
+            1,
+            "Anna/Björk",
+            "abjork@kth.se",
+            &["../../evil.sh", "..", "sub/dir.pdf"],
+        );
+
+        for layout in [Layout::Flat, Layout::PerStudent] {
+            let options = DownloadOptions {
+                layout,
+                ..DownloadOptions::default()
+            };
+            for (path, _) in plan_files(&submission, folder, &Utc::now(), &options) {
+                let relative = path.strip_prefix(folder).unwrap();
+                let depth = match layout {
+                    Layout::Flat => 1,
+                    Layout::PerStudent => 2,
+                };
+                assert_eq!(relative.components().count(), depth, "{:?}", path);
+                assert!(relative
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_))));
+            }
+        }
+    }
+
+    #[test]
+    fn rendered_filename_template_stays_in_the_folder() {
+        let folder = Path::new("downloads");
+        let submission = submission(1, "../Anna", "aberg@kth.se", &["../report.pdf"]);
+        let options = DownloadOptions {
+            layout: Layout::Flat,
+            filename_template: Some("../{name}/{original}".parse().unwrap()),
+            ..DownloadOptions::default()
+        };
+
+        let planned = plan_files(&submission, folder, &Utc::now(), &options);
+        let relative = planned[0].0.strip_prefix(folder).unwrap();
+        assert_eq!(relative, Path::new(".._.._Anna_.._report.pdf"));
+    }
+
+    #[test]
+    fn attachments_with_the_same_name_get_different_paths() {
+        let folder = Path::new("downloads");
+        let submission = submission(
+            1,
+            "Anna Berg",
+            "aberg@kth.se",
+            &["report.pdf", "report.pdf"],
+        );
+
+        for layout in [Layout::Flat, Layout::PerStudent] {
+            let options = DownloadOptions {
+                layout,
+                ..DownloadOptions::default()
+            };
+            let paths: Vec<PathBuf> = plan_files(&submission, folder, &Utc::now(), &options)
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            assert_eq!(paths.len(), 2);
+            assert_ne!(paths[0], paths[1]);
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn unique_names_numbers_duplicates() {
+        assert_eq!(
+            unique_names(names(&["main.rs", "lib.rs", "main.rs"])),
+            names(&["main-1.rs", "lib.rs", "main-2.rs"])
+        );
+        assert_eq!(
+            unique_names(names(&["README", "README"])),
+            names(&["README-1", "README-2"])
+        );
+    }
+
+    #[test]
+    fn unique_names_skips_names_already_taken() {
+        assert_eq!(
+            unique_names(names(&["main.rs", "main-1.rs", "main.rs"])),
+            names(&["main-2.rs", "main-1.rs", "main-3.rs"])
+        );
+    }
+
+    #[test]
+    fn normalize_name_strips_diacritics_and_case() {
+        assert_eq!(normalize_name("Åsa Lindén"), "asa linden");
+        assert_eq!(normalize_name("Åsa Lindén"), normalize_name("asa linden"));
+        assert_eq!(name_similarity("Åsa Lindén", "asa linden"), 1.0);
+    }
+
+    #[test]
+    fn name_similarity_reads_family_name_first() {
+        assert_eq!(name_similarity("Berg, Anna", "Anna Berg"), 1.0);
+        assert_eq!(name_similarity("Anna Berg", "Berg, Anna"), 1.0);
+        assert_eq!(name_similarity("Berg Anna", "Anna Berg"), 1.0);
+        assert!(name_similarity("Berg, Anna", "Bo Ek") < DEFAULT_FUZZY_THRESHOLD);
+    }
+
+    fn booking(name: &str, email: &str) -> Booking {
+        Booking {
+            time: Utc::now(),
+            name: name.to_string(),
+            email: Email::OtherEmail(email.to_string()),
+            event: "lab1".to_string(),
+        }
+    }
+
+    /// The login of the submission matched to the booking named `name`.
+    fn matched_login<'a>(
+        matches: &'a HashMap<Booking, Option<Match>>,
+        name: &str,
+    ) -> Option<&'a str> {
+        matches
+            .iter()
+            .find(|(booking, _)| booking.name == name)
+            .and_then(|(_, m)| m.as_ref())
+            .map(|m| m.submission.user.email())
+    }
+
+    #[test]
+    fn match_submissions_tells_similar_names_apart() {
+        let bookings = [
+            booking("Anna Berg", "anna@gmail.com"),
+            booking("Anna Bergh", "bergh@gmail.com"),
+        ];
+        let submissions = [
+            submission(1, "Anna Bergh", "abergh@kth.se", &[]),
+            submission(2, "Anna Berg", "aberg@kth.se", &[]),
+        ];
+
+        let matches = match_submissions(&bookings, &submissions, &MatchOptions::default());
+        assert_eq!(matched_login(&matches, "Anna Berg"), Some("aberg@kth.se"));
+        assert_eq!(matched_login(&matches, "Anna Bergh"), Some("abergh@kth.se"));
+    }
+
+    #[test]
+    fn match_submissions_pairs_most_similar_names_first() {
+        // "Johanne Svensson" is similar enough to "Johanna Svensson" to be
+        // matched to her if asked first, but the exact pair goes first.
+        let bookings = [
+            booking("Johan Svensson", "johan@gmail.com"),
+            booking("Johanna Svensson", "johanna@gmail.com"),
+            booking("Johanne Svensson", "johanne@gmail.com"),
+        ];
+        let submissions = [
+            submission(1, "Johanna Svensson", "johanna@kth.se", &[]),
+            submission(2, "Johan Svenson", "johan@kth.se", &[]),
+        ];
+
+        let matches = match_submissions(&bookings, &submissions, &MatchOptions::default());
+        assert_eq!(
+            matched_login(&matches, "Johan Svensson"),
+            Some("johan@kth.se")
+        );
+        assert_eq!(
+            matched_login(&matches, "Johanna Svensson"),
+            Some("johanna@kth.se")
+        );
+        assert_eq!(matched_login(&matches, "Johanne Svensson"), None);
+
+        let mut logins: Vec<_> = matches
+            .values()
+            .flatten()
+            .map(|m| m.submission.user.email())
+            .collect();
+        logins.sort();
+        logins.dedup();
+        assert_eq!(logins.len(), matches.values().flatten().count());
+    }
+
+    /// Anna uploaded the report of group 7 for both herself and Erik, who
+    /// only has an `unsubmitted` placeholder.
+    fn group_submissions() -> Vec<Submission> {
+        let mut uploader = submission(1, "Anna Berg", "aberg@kth.se", &["report.pdf"]);
+        uploader.body = Some("<p>See the report</p>".to_string());
+        uploader.url = Some("https://example.com/report".to_string());
+        let mut member = submission(2, "Erik Björk", "ebjork@kth.se", &[]);
+        member.workflow_state = Some("unsubmitted".to_string());
+        for submission in [&mut uploader, &mut member] {
+            submission.group = Some(Group {
+                id: Some(7),
+                name: Some("Group 7".to_string()),
+            });
+        }
+        vec![uploader, member]
+    }
+
+    #[test]
+    fn group_member_gets_the_uploaded_files() {
+        let bookings = [
+            booking("Anna Berg", "aberg@kth.se"),
+            booking("Erik Björk", "ebjork@kth.se"),
+        ];
+        let options = MatchOptions {
+            group: true,
+            ..MatchOptions::default()
+        };
+        let matches = match_bookings(&bookings, &group_submissions(), &options);
+
+        let uploader = &matches[&bookings[0]].as_ref().unwrap().submission;
+        let member = &matches[&bookings[1]].as_ref().unwrap().submission;
+        assert_eq!(member.id, 2);
+        assert_eq!(member.user.email(), "ebjork@kth.se");
+        assert_eq!(member.attachments()[0].url, uploader.attachments()[0].url);
+        assert_eq!(member.body, uploader.body);
+        assert_eq!(member.url, uploader.url);
+    }
+
+    #[test]
+    fn group_member_without_upload_is_matched_to_the_group_files() {
+        let bookings = [booking("Erik Björk", "ebjork@kth.se")];
+        let options = MatchOptions {
+            group: true,
+            ..MatchOptions::default()
+        };
+        let (matches, not_submitted) = match_submitted(&bookings, &group_submissions(), &options);
+
+        let m = matches[&bookings[0]].as_ref().unwrap();
+        assert_eq!(m.submission.user.email(), "ebjork@kth.se");
+        assert_eq!(m.submission.attachments()[0].display_name(), "report.pdf");
+        assert!(not_submitted.is_empty());
+    }
+
+    #[test]
+    fn group_without_upload_has_not_submitted() {
+        let submissions: Vec<_> = group_submissions().into_iter().skip(1).collect();
+        let bookings = [booking("Erik Björk", "ebjork@kth.se")];
+        let options = MatchOptions {
+            group: true,
+            ..MatchOptions::default()
+        };
+        let (matches, not_submitted) = match_submitted(&bookings, &submissions, &options);
+
+        assert!(matches[&bookings[0]].is_none());
+        assert_eq!(not_submitted[&bookings[0]].name, "Erik Björk");
+    }
+
+    fn link_header(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(LINK, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn next_page_is_none_without_link_header() {
+        assert_eq!(next_page(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn next_page_follows_rel_next() {
+        let headers = link_header(
+            "<https://canvas.kth.se/api/v1/courses?page=1>; rel=\"current\", \
+             <https://canvas.kth.se/api/v1/courses?page=2>; rel=\"next\", \
+             <https://canvas.kth.se/api/v1/courses?page=5>; rel=\"last\"",
+        );
+        assert_eq!(
+            next_page(&headers).as_deref(),
+            Some("https://canvas.kth.se/api/v1/courses?page=2")
+        );
+    }
+
+    #[test]
+    fn next_page_is_none_on_the_last_page() {
+        let headers = link_header(
+            "<https://canvas.kth.se/api/v1/courses?page=5>; rel=\"current\", \
+             <https://canvas.kth.se/api/v1/courses?page=5>; rel=\"last\"",
+        );
+        assert_eq!(next_page(&headers), None);
+    }
+
+    /// Serves HTTP on a local port, answering request number `n` with
+    /// `respond(n)`, or never answering if it is `None`. Returns the
+    /// address and the number of requests so far.
+    async fn serve(
+        respond: impl Fn(usize) -> Option<String> + Send + Sync + 'static,
+    ) -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let respond = Arc::new(respond);
+
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let _ = socket.read(&mut buf).await;
+                    match respond(n) {
+                        Some(response) => {
+                            let _ = socket.write_all(response.as_bytes()).await;
+                        }
+                        None => tokio::time::sleep(Duration::from_secs(10)).await,
+                    }
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[tokio::test]
+    async fn fetches_one_page_without_link_header() {
+        let (addr, requests) = serve(|_| Some(ok("[1, 2]"))).await;
+        let canvas = Canvas::with_base_url(String::new(), format!("http://{}", addr)).unwrap();
+
+        let data: Vec<u32> = canvas.get_paginated_data("/courses", &[]).await.unwrap();
+        assert_eq!(data, [1, 2]);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn interrupted_download_leaves_no_file() {
+        // Promises 100 bytes, but the connection closes after 10.
+        let (addr, _) =
+            serve(|_| Some("HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n0123456789".to_string()))
+                .await;
+        let canvas = Canvas::with_base_url(String::new(), format!("http://{}", addr)).unwrap();
+        let attachment: Attachment = serde_json::from_value(serde_json::json!({
+            "url": format!("http://{}/files/1", addr),
+            "display_name": "report.pdf",
+        }))
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.pdf");
+
+        assert!(canvas
+            .download_attachment(&attachment, &path)
+            .await
+            .is_err());
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn expired_download_link_is_refreshed() {
+        let xml_error = |content_type: &str| {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: 9\r\nConnection: close\r\n\r\n<Error/>\n",
+                content_type
+            )
+        };
+        let expired = [
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+            xml_error("application/xml"),
+            xml_error("text/xml; charset=utf-8"),
+        ];
+        for expired in expired {
+            // The signed link has expired, `/files/1` gives a fresh one, and
+            // the fresh link has the file.
+            let addr = Arc::new(std::sync::OnceLock::new());
+            let fresh_addr = addr.clone();
+            let (server, _) = serve(move |n| match n {
+                0 => Some(expired.clone()),
+                1 => Some(ok(&serde_json::json!({
+                    "id": 1,
+                    "url": format!("http://{}/fresh", fresh_addr.get().unwrap()),
+                    "display_name": "report.pdf",
+                })
+                .to_string())),
+                _ => Some(ok("fresh report")),
+            })
+            .await;
+            addr.set(server).unwrap();
+
+            let canvas =
+                Canvas::with_base_url(String::new(), format!("http://{}", server)).unwrap();
+            let attachment: Attachment = serde_json::from_value(serde_json::json!({
+                "id": 1,
+                "url": format!("http://{}/expired", server),
+                "display_name": "report.pdf",
+            }))
+            .unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("report.pdf");
+
+            let bytes = canvas
+                .download_fresh_attachment(&attachment, &path)
+                .await
+                .unwrap();
+            assert_eq!(bytes, 12);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "fresh report");
+        }
+    }
+
+    /// A path `name` in a temporary folder that is taken by a non-empty
+    /// folder, so that renaming a file to it fails.
+    fn blocked_path(name: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(path.join("in-the-way")).unwrap();
+        (dir, path)
+    }
+
+    #[tokio::test]
+    async fn failed_text_write_leaves_no_part_file() {
+        let canvas = Canvas::new(String::new()).unwrap();
+        let (_dir, path) = blocked_path("answer.html");
+
+        let result = canvas
+            .download_file(
+                path.clone(),
+                Source::Inline("<p>42</p>".to_string()),
+                &DownloadOptions::default(),
+            )
+            .await;
+        assert!(result.is_err());
+        assert!(!part_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn failed_fixture_copy_leaves_no_part_file() {
+        let fixtures = tempfile::tempdir().unwrap();
+        std::fs::write(fixtures.path().join("report.pdf"), "report").unwrap();
+        let canvas = Canvas::new(String::new())
+            .unwrap()
+            .with_fixtures(fixtures.path().to_path_buf());
+        let attachment: Attachment = serde_json::from_value(serde_json::json!({
+            "url": "report.pdf",
+            "display_name": "report.pdf",
+        }))
+        .unwrap();
+        let (_dir, path) = blocked_path("report.pdf");
+
+        assert!(canvas
+            .download_attachment(&attachment, &path)
+            .await
+            .is_err());
+        assert!(!part_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn broken_zip_is_kept_without_extracting() {
+        let canvas = Canvas::new(String::new()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lab1.zip");
+        let options = DownloadOptions {
+            extract_zips: true,
+            delete_extracted_zips: true,
+            ..DownloadOptions::default()
+        };
+
+        let file = canvas
+            .download_file(
+                path.clone(),
+                Source::Inline("not a zip".to_string()),
+                &options,
+            )
+            .await
+            .unwrap();
+        assert_eq!(file.path, path);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn extract_zip_stops_at_the_size_limit() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lab1.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for name in ["a.txt", "b.txt"] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(&[b'x'; 100]).unwrap();
+        }
+        zip.finish().unwrap();
+
+        assert!(extract_zip(&path, 200).is_ok());
+        assert!(matches!(
+            extract_zip(&path, 150),
+            Err(CanvasError::Extract { .. })
+        ));
+        assert!(!dir.path().join("lab1").join("b.txt").exists());
+    }
+
+    fn retry_after_header(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_reads_seconds() {
+        assert_eq!(
+            retry_after(&retry_after_header("3")),
+            Some(Duration::from_secs(3))
+        );
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        assert_eq!(
+            retry_after(&retry_after_header("86400")),
+            Some(MAX_RETRY_AFTER)
+        );
+        let tomorrow = (Utc::now() + chrono::Duration::days(1)).to_rfc2822();
+        assert_eq!(
+            retry_after(&retry_after_header(&tomorrow)),
+            Some(MAX_RETRY_AFTER)
+        );
+    }
+
+    #[test]
+    fn filename_template_identifies_student() {
+        let identifies = |template: &str| {
+            template
+                .parse::<FilenameTemplate>()
+                .unwrap()
+                .identifies_student()
+        };
+        assert!(FilenameTemplate::default().identifies_student());
+        assert!(identifies("{kth_id}-{original}"));
+        assert!(identifies("{submission_id}.pdf"));
+        assert!(!identifies("{original}"));
+        assert!(!identifies("{time}-{original}"));
+    }
+
+    #[test]
+    fn invalid_token_is_an_error() {
+        assert!(matches!(
+            Canvas::new("abc\ndef".to_string()),
+            Err(CanvasError::InvalidToken)
+        ));
+        assert!(Canvas::new("abc~def".to_string()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn retries_timed_out_requests() {
+        // The first two requests never get an answer, the third does.
+        let (addr, requests) = serve(|n| (n >= 2).then(|| ok("[]"))).await;
+        let canvas = Canvas::with_base_url(String::new(), format!("http://{}", addr))
+            .unwrap()
+            .with_retries(3, Duration::from_millis(1));
+        let request = canvas
+            .client
+            .get(format!("http://{}/courses", addr))
+            .timeout(Duration::from_millis(100));
+
+        let resp = canvas.send_with_retry(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
     }
 }
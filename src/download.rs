@@ -0,0 +1,467 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::{
+    canvas::{
+        by_name_similarity, match_submitted, Canvas, CanvasError, DownloadOptions, DownloadedFile,
+        Match, MatchMethod, MatchOptions, Submission, User,
+    },
+    manifest::Manifest,
+    remores::{Booking, Remores},
+    RemoresDlError,
+};
+
+/// Everything needed to download the submissions matching a set of
+/// REMORES bookings.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// KTH IDs of the teachers or TAs whose bookings to look for.
+    pub kth_ids: Vec<String>,
+    pub course: u32,
+    pub assignment: u32,
+    /// The folder the submissions are downloaded to.
+    pub folder: PathBuf,
+    pub matching: MatchOptions,
+    pub download: DownloadOptions,
+    /// How many submissions to download at the same time.
+    pub concurrency: usize,
+    /// Only include bookings at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Only include bookings before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// Only download submissions made after the due date.
+    pub late_only: bool,
+    /// Only download submissions in these workflow states, e.g. `graded`.
+    /// All submissions are downloaded when empty.
+    pub states: Vec<String>,
+    /// Match the bookings and plan the files, without downloading.
+    pub dry_run: bool,
+    /// Skip REMORES sublists that can't be fetched or parsed, with a
+    /// warning, instead of failing.
+    pub keep_going: bool,
+}
+
+/// Progress of `run_download`, reported as it happens so that a caller
+/// can show it.
+pub enum Progress<'a> {
+    /// This many bookings were found on REMORES.
+    Bookings(usize),
+    /// This many bookings were matched to a submission.
+    Matched(usize),
+    /// Something about a booking or its submission that needs attention.
+    Warning(&'a Warning),
+    /// This many submissions are about to be downloaded.
+    Downloading(usize),
+    /// A submission was downloaded, or failed to.
+    Downloaded(&'a Submission, &'a Result<Vec<DownloadedFile>, CanvasError>),
+}
+
+/// Something about a booking or its submission that needs attention.
+#[derive(Debug, Clone)]
+pub enum Warning {
+    /// The REMORES sublist could not be fetched or parsed, and its bookings
+    /// are missing.
+    SkippedSublist { sub_list: String, reason: String },
+    /// No submission was found for the booking. `closest` is the student
+    /// of the unmatched submission with the most similar name, if any.
+    Unmatched {
+        booking: Booking,
+        closest: Option<(User, f64)>,
+    },
+    /// The student of the booking has not submitted anything, there is only
+    /// an empty placeholder submission.
+    NotSubmitted { booking: Booking, user: User },
+    /// The booking was matched by name similarity, which should be checked.
+    FuzzyMatch {
+        booking: Booking,
+        user: User,
+        score: f64,
+        /// Another submission was almost as similar.
+        ambiguous: bool,
+    },
+    /// The submission was matched to several bookings by the same student,
+    /// and is only downloaded once, under the latest booking `time`.
+    Duplicate {
+        user: User,
+        bookings: usize,
+        time: DateTime<Utc>,
+    },
+    /// The submission was made after the due date.
+    Late { user: User, seconds_late: u64 },
+    /// An attachment of the submission is an empty file.
+    EmptyAttachment { user: User, name: String },
+}
+
+/// The outcome of `run_download`.
+#[derive(Debug)]
+pub struct DownloadReport {
+    /// Every booking, and the submission it was matched to.
+    pub matches: HashMap<Booking, Option<Match>>,
+    /// The files written for each submission id, or the files that would
+    /// have been written in a dry run.
+    pub files: HashMap<u64, Vec<PathBuf>>,
+    /// The size of each downloaded file.
+    pub sizes: HashMap<PathBuf, u64>,
+    /// Files that were already present and not downloaded again, or would
+    /// not be in a dry run.
+    pub skipped: HashSet<PathBuf>,
+    /// Bytes downloaded, not counting files that already existed.
+    pub bytes: u64,
+    /// How long downloading took.
+    pub elapsed: Duration,
+    /// Attachments not downloaded because of their extension.
+    pub excluded: usize,
+    /// Submissions that could not be downloaded.
+    pub failed: Vec<(Submission, CanvasError)>,
+    pub warnings: Vec<Warning>,
+}
+
+/// Counts of what happened in a download, for the summary at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadSummary {
+    pub bookings: usize,
+    /// Bookings matched by a mapping file, by email or by the local part of
+    /// the email.
+    pub exact: usize,
+    /// Bookings matched by name similarity.
+    pub fuzzy: usize,
+    pub unmatched: usize,
+    /// Files written, or that would be written in a dry run.
+    pub files: usize,
+    /// Files already present, which were not downloaded again.
+    pub skipped: usize,
+    /// Attachments not downloaded because of their extension.
+    pub excluded: usize,
+    /// Submissions that could not be downloaded.
+    pub failures: usize,
+    /// Bytes downloaded, not counting files that already existed.
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl DownloadSummary {
+    pub fn matched(&self) -> usize {
+        self.exact + self.fuzzy
+    }
+}
+
+impl DownloadReport {
+    /// Counts the bookings, matches, files and failures of the download.
+    pub fn summary(&self) -> DownloadSummary {
+        let fuzzy = self
+            .matches
+            .values()
+            .flatten()
+            .filter(|m| matches!(m.method, MatchMethod::Fuzzy { .. }))
+            .count();
+        let unmatched = self.unmatched().count();
+        DownloadSummary {
+            bookings: self.matches.len(),
+            exact: self.matches.len() - unmatched - fuzzy,
+            fuzzy,
+            unmatched,
+            files: self.files.values().map(Vec::len).sum::<usize>() - self.skipped.len(),
+            skipped: self.skipped.len(),
+            excluded: self.excluded,
+            failures: self.failed.len(),
+            bytes: self.bytes,
+            elapsed: self.elapsed,
+        }
+    }
+
+    /// Bookings that no submission was found for.
+    pub fn unmatched(&self) -> impl Iterator<Item = &Booking> {
+        self.matches
+            .iter()
+            .filter(|(_, m)| m.is_none())
+            .map(|(booking, _)| booking)
+    }
+
+    /// Fails with `RemoresDlError::NoSubmission` if any booking has no
+    /// submission.
+    pub fn require_matched(&self) -> Result<(), RemoresDlError> {
+        match self.unmatched().count() {
+            0 => Ok(()),
+            bookings => Err(RemoresDlError::NoSubmission { bookings }),
+        }
+    }
+
+    /// Writes the bookings that no submission was found for, with the most
+    /// similar unmatched student, as JSON if `path` ends with `.json` and
+    /// as CSV otherwise.
+    pub fn write_unmatched(&self, path: &Path) -> Result<(), RemoresDlError> {
+        let mut rows: Vec<UnmatchedRow> = self
+            .warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                Warning::Unmatched { booking, closest } => Some(UnmatchedRow {
+                    time: booking.time,
+                    name: &booking.name,
+                    email: booking.email.to_string(),
+                    reason: match closest {
+                        Some(_) => "below_threshold",
+                        None => "no_submission",
+                    },
+                    closest_name: closest.as_ref().map(|(user, _)| user.name.as_str()),
+                    closest_login: closest.as_ref().map(|(user, _)| user.identifier()),
+                    closest_score: closest.as_ref().map(|(_, score)| *score),
+                }),
+                Warning::NotSubmitted { booking, user } => Some(UnmatchedRow {
+                    time: booking.time,
+                    name: &booking.name,
+                    email: booking.email.to_string(),
+                    reason: "not_submitted",
+                    closest_name: Some(&user.name),
+                    closest_login: Some(user.identifier()),
+                    closest_score: None,
+                }),
+                _ => None,
+            })
+            .collect();
+        rows.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.name.cmp(b.name)));
+
+        if path.extension().is_some_and(|ext| ext == "json") {
+            std::fs::write(path, serde_json::to_string_pretty(&rows)?)?;
+        } else {
+            let mut writer = csv::Writer::from_path(path)?;
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// The manifest of the download, see `Manifest::new`.
+    pub fn manifest(&self, folder: &Path, with_grades: bool) -> Manifest {
+        Manifest::new(&self.matches, &self.files, &self.sizes, folder, with_grades)
+    }
+}
+
+/// A booking that no submission was found for, and why.
+#[derive(Serialize)]
+struct UnmatchedRow<'a> {
+    time: DateTime<Utc>,
+    name: &'a str,
+    email: String,
+    /// `no_submission` if every submission went to another booking,
+    /// `below_threshold` if the most similar name was not similar enough, or
+    /// `not_submitted` if the student has not submitted anything.
+    reason: &'a str,
+    closest_name: Option<&'a str>,
+    closest_login: Option<&'a str>,
+    closest_score: Option<f64>,
+}
+
+/// Finds the bookings on REMORES, matches them to submissions on Canvas,
+/// and downloads the submissions to `options.folder`. Nothing is printed,
+/// `progress` is called as the download goes along instead.
+pub async fn run_download(
+    canvas: &Canvas,
+    remores: &Remores,
+    options: &RunOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<DownloadReport, RemoresDlError> {
+    let mut warnings = vec![];
+    let mut bookings = if options.keep_going {
+        let (bookings, skipped) = remores
+            .get_bookings_for_many_skipping(&options.kth_ids)
+            .await?;
+        for (sub_list, e) in skipped {
+            warnings.push(Warning::SkippedSublist {
+                sub_list,
+                reason: e.to_string(),
+            });
+        }
+        bookings
+    } else {
+        remores.get_bookings_for_many(&options.kth_ids).await?
+    };
+    bookings.retain(|booking| {
+        options.from.is_none_or(|from| booking.time >= from)
+            && options.until.is_none_or(|until| booking.time < until)
+    });
+    progress(Progress::Bookings(bookings.len()));
+
+    let mut include = options.matching.include.clone();
+    include.group |= options.matching.group;
+    let submissions = canvas
+        .get_submissions(&options.course, &options.assignment, &include)
+        .await?;
+    let (matches, not_submitted) = match_submitted(&bookings, &submissions, &options.matching);
+
+    let matched: HashSet<u64> = matches
+        .values()
+        .flatten()
+        .map(|m| m.submission.id)
+        .collect();
+    progress(Progress::Matched(
+        matches.values().filter(|m| m.is_some()).count(),
+    ));
+
+    for (booking, m) in &matches {
+        match m {
+            None => {
+                if let Some(user) = not_submitted.get(booking) {
+                    warnings.push(Warning::NotSubmitted {
+                        booking: booking.clone(),
+                        user: user.clone(),
+                    });
+                    continue;
+                }
+                let unmatched = submissions
+                    .iter()
+                    .filter(|s| s.is_submitted() && !matched.contains(&s.id));
+                let closest = by_name_similarity(&booking.name, unmatched)
+                    .first()
+                    .map(|(submission, score)| (submission.user.clone(), *score));
+                warnings.push(Warning::Unmatched {
+                    booking: booking.clone(),
+                    closest,
+                })
+            }
+            Some(Match {
+                submission,
+                method: MatchMethod::Fuzzy { score, ambiguous },
+            }) => warnings.push(Warning::FuzzyMatch {
+                booking: booking.clone(),
+                user: submission.user.clone(),
+                score: *score,
+                ambiguous: *ambiguous,
+            }),
+            Some(_) => {}
+        }
+    }
+
+    // A student who booked several times is downloaded once, under the
+    // time of their latest booking.
+    let mut latest: HashMap<u64, (&Submission, DateTime<Utc>, usize)> = HashMap::new();
+    for (booking, m) in &matches {
+        let Some(Match { submission, .. }) = m else {
+            continue;
+        };
+        latest
+            .entry(submission.id)
+            .and_modify(|(_, time, count)| {
+                *time = (*time).max(booking.time);
+                *count += 1;
+            })
+            .or_insert((submission, booking.time, 1));
+    }
+
+    for (submission, time, count) in latest.values() {
+        if *count > 1 {
+            warnings.push(Warning::Duplicate {
+                user: submission.user.clone(),
+                bookings: *count,
+                time: *time,
+            });
+        }
+        if submission.is_late() {
+            warnings.push(Warning::Late {
+                user: submission.user.clone(),
+                seconds_late: submission.seconds_late.unwrap_or_default(),
+            });
+        }
+        for attachment in submission.attachments() {
+            if attachment.size() == Some(0) {
+                warnings.push(Warning::EmptyAttachment {
+                    user: submission.user.clone(),
+                    name: attachment.display_name().to_string(),
+                });
+            }
+        }
+    }
+    for warning in &warnings {
+        progress(Progress::Warning(warning));
+    }
+
+    let downloads = latest
+        .into_values()
+        .filter(|(submission, _, _)| !options.late_only || submission.is_late())
+        .filter(|(submission, _, _)| submission.in_states(&options.states))
+        .map(|(submission, time, _)| (submission.clone(), time))
+        .collect::<Vec<_>>();
+
+    let excluded = downloads
+        .iter()
+        .map(|(submission, _)| canvas.excluded_count(submission, &options.download))
+        .sum();
+
+    let mut files = HashMap::new();
+    let mut sizes = HashMap::new();
+    let mut skipped = HashSet::new();
+    let mut bytes = 0;
+    let mut failed = vec![];
+    let start = Instant::now();
+
+    if options.dry_run {
+        for (submission, time) in &downloads {
+            let planned =
+                canvas.planned_files(submission, &options.folder, time, &options.download);
+            let mut paths = vec![];
+            for file in planned {
+                if file.skipped {
+                    skipped.insert(file.path.clone());
+                }
+                paths.push(file.path);
+            }
+            files.insert(submission.id, paths);
+        }
+    } else {
+        progress(Progress::Downloading(downloads.len()));
+        tokio::fs::create_dir_all(&options.folder).await?;
+
+        let mut results = canvas.download_submissions(
+            downloads,
+            &options.folder,
+            &options.download,
+            options.concurrency,
+        );
+        while let Some((submission, result)) = results.next().await {
+            progress(Progress::Downloaded(&submission, &result));
+            // Files written before another file of the submission failed
+            // are still recorded.
+            let written = match &result {
+                Ok(downloaded) => Some(downloaded),
+                Err(CanvasError::FilesFailed { files, .. }) if !files.is_empty() => Some(files),
+                Err(_) => None,
+            };
+            if let Some(written) = written {
+                let mut paths = vec![];
+                for file in written {
+                    if file.skipped {
+                        skipped.insert(file.path.clone());
+                    } else {
+                        bytes += file.bytes;
+                    }
+                    sizes.insert(file.path.clone(), file.bytes);
+                    paths.push(file.path.clone());
+                }
+                files.insert(submission.id, paths);
+            }
+            if let Err(e) = result {
+                failed.push((submission, e));
+            }
+        }
+    }
+
+    Ok(DownloadReport {
+        matches,
+        files,
+        sizes,
+        skipped,
+        bytes,
+        elapsed: start.elapsed(),
+        excluded,
+        failed,
+        warnings,
+    })
+}
@@ -1,2 +1,99 @@
 pub mod canvas;
+mod download;
+pub mod manifest;
 pub mod remores;
+
+use thiserror::Error;
+
+pub use download::{run_download, DownloadReport, DownloadSummary, Progress, RunOptions, Warning};
+
+/// The errors of a download and of writing its reports. The failures a
+/// caller is likely to handle, like a rejected token, have their own
+/// variants, other Canvas and REMORES errors are kept as they are.
+#[derive(Error, Debug)]
+pub enum RemoresDlError {
+    /// Canvas rejected the API token.
+    #[error("Canvas rejected the API token — generate a new one at {settings_url}")]
+    CanvasAuth { settings_url: String },
+    /// The REMORES repository has no booking lists, so the name is likely
+    /// wrong.
+    #[error(
+        "REMORES repository {repository} has no booking lists, is the repository name correct?"
+    )]
+    RemoresRepoNotFound { repository: String },
+    /// No submission was found for some bookings.
+    #[error("No submission found for {bookings} bookings")]
+    NoSubmission { bookings: usize },
+    /// A submission has no attachments, text or link to download.
+    #[error("No attachments, text or link found for submission")]
+    NoAttachments,
+    #[error(transparent)]
+    Canvas(canvas::CanvasError),
+    #[error(transparent)]
+    Remores(remores::RemoresError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+}
+
+impl From<canvas::CanvasError> for RemoresDlError {
+    fn from(e: canvas::CanvasError) -> Self {
+        match e {
+            canvas::CanvasError::Unauthorized { settings_url } => {
+                RemoresDlError::CanvasAuth { settings_url }
+            }
+            canvas::CanvasError::NoContent => RemoresDlError::NoAttachments,
+            e => RemoresDlError::Canvas(e),
+        }
+    }
+}
+
+impl From<remores::RemoresError> for RemoresDlError {
+    fn from(e: remores::RemoresError) -> Self {
+        match e {
+            remores::RemoresError::NoBookingLists { repository } => {
+                RemoresDlError::RemoresRepoNotFound { repository }
+            }
+            e => RemoresDlError::Remores(e),
+        }
+    }
+}
+
+/// The User-Agent sent with every request to Canvas and REMORES.
+const USER_AGENT: &str = concat!("remores-dl/", env!("CARGO_PKG_VERSION"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_errors_map_to_named_variants() {
+        let auth = canvas::CanvasError::Unauthorized {
+            settings_url: "https://canvas.kth.se/profile/settings".to_string(),
+        };
+        assert!(matches!(
+            RemoresDlError::from(auth),
+            RemoresDlError::CanvasAuth { .. }
+        ));
+        assert!(matches!(
+            RemoresDlError::from(canvas::CanvasError::NoContent),
+            RemoresDlError::NoAttachments
+        ));
+        let repo = remores::RemoresError::NoBookingLists {
+            repository: "dd1337".to_string(),
+        };
+        assert!(matches!(
+            RemoresDlError::from(repo),
+            RemoresDlError::RemoresRepoNotFound { repository } if repository == "dd1337"
+        ));
+        assert!(matches!(
+            RemoresDlError::from(canvas::CanvasError::ExpiredUrl),
+            RemoresDlError::Canvas(canvas::CanvasError::ExpiredUrl)
+        ));
+    }
+}
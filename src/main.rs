@@ -1,37 +1,600 @@
-use std::{fs, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use clap::{Parser, Subcommand};
-use remores_dl::{canvas::Canvas, remores::Remores};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use dialoguer::{Input, Select};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use remores_dl::{
+    canvas::{
+        read_mapping, AssignmentOptions, Attempt, Canvas, DownloadOptions, DownloadProgress,
+        FilenameTemplate, Layout, MatchMethod, MatchOptions, Submission, SubmissionIncludes,
+        DEFAULT_API_URL, DEFAULT_ATTACHMENT_CONCURRENCY, DEFAULT_FUZZY_THRESHOLD,
+        DEFAULT_MAX_RETRIES, DEFAULT_RETRY_BASE_DELAY, DEFAULT_TIMEOUT,
+    },
+    remores::{
+        default_cache_dir, Remores, DEFAULT_CACHE_TTL, DEFAULT_CONCURRENCY, DEFAULT_REMORES_URL,
+        TIMEZONE,
+    },
+    run_download, DownloadSummary, Progress, RunOptions, Warning,
+};
+use serde::{Deserialize, Serialize};
+use tracing::Level;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Csv,
+}
+
+/// Like `println!`, but for progress output that `--quiet` suppresses.
+macro_rules! status {
+    ($cli:expr, $($arg:tt)*) => {
+        if !$cli.quiet {
+            println!($($arg)*);
+        }
+    };
+}
 
+// Parsed once at startup, so the size of `Download` doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     #[clap(about = "List available courses on Canvas where you are either a teacher or a TA.")]
     Courses,
     #[clap(about = "List all available assignments for a specific course on Canvas.")]
-    Assignments { course_id: String },
+    Assignments {
+        course_id: String,
+        #[clap(
+            long,
+            value_parser = parse_date,
+            help = "Only list assignments due after this date, e.g. `2024-05-01` or `2024-05-01 13:00`"
+        )]
+        after: Option<DateTime<Utc>>,
+        #[clap(
+            long,
+            value_parser = parse_date,
+            help = "Only list assignments due before this date"
+        )]
+        before: Option<DateTime<Utc>>,
+        #[clap(
+            long,
+            help = "Also list assignments without a due date when filtering by date"
+        )]
+        include_undated: bool,
+        #[clap(long, help = "Also list unpublished assignments")]
+        include_unpublished: bool,
+        #[clap(
+            long,
+            help = "Also list assignments that are not graded pass/fail, by points or by letter"
+        )]
+        all_grading_types: bool,
+    },
+    #[clap(about = "List the students enrolled in a course on Canvas.")]
+    Students {
+        #[clap(short, long, help = "The Canvas course ID")]
+        course: u32,
+    },
+    #[clap(about = "List the submissions of an assignment on Canvas, without matching bookings.")]
+    Submissions {
+        #[clap(short, long, help = "The Canvas course ID")]
+        course: u32,
+        #[clap(short, long, help = "The Canvas assignment ID")]
+        assignment: u32,
+        #[clap(long, help = "Also list students who have not submitted")]
+        all: bool,
+        #[clap(
+            long,
+            value_parser = ["submitted", "graded", "pending_review", "unsubmitted"],
+            help = "Only list submissions in this state. Can be given several times"
+        )]
+        state: Vec<String>,
+    },
     #[clap(about = "Download submissions from Canvas, matching bookings from REMORES.")]
     Download {
         #[clap(
+            env = "REMORES_DL_DIR",
             default_value = "downloads",
-            help = "The folder to download the submissions to"
+            help = "The folder to download the submissions to, relative to --base-dir if given"
         )]
         folder: String,
+        #[clap(
+            long,
+            env = "REMORES_DL_BASE_DIR",
+            help = "The folder that a relative download folder is in, e.g. `~/exams`"
+        )]
+        base_dir: Option<String>,
+        #[clap(
+            long,
+            help = "Pick the course and assignment from a menu, and ask for the repository and KTH ID, unless given"
+        )]
+        interactive: bool,
+        #[clap(
+            short,
+            long,
+            required_unless_present = "interactive",
+            help = "The REMORES repository name"
+        )]
+        repo: Option<String>,
+        #[clap(
+            short,
+            long,
+            required_unless_present = "interactive",
+            help = "Your KTH ID, eg. `asalamon`. Can be given several times"
+        )]
+        kth_id: Vec<String>,
+        #[clap(
+            short,
+            long,
+            required_unless_present = "interactive",
+            help = "The Canvas course ID"
+        )]
+        course: Option<u32>,
+        #[clap(
+            short,
+            long,
+            required_unless_present = "interactive",
+            help = "The Canvas assignment ID"
+        )]
+        assignment: Option<u32>,
+        #[clap(
+            long,
+            default_value_t = 4,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+            help = "How many submissions to download at the same time"
+        )]
+        concurrency: usize,
+        #[clap(
+            long,
+            default_value_t = DEFAULT_ATTACHMENT_CONCURRENCY,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+            help = "How many files of each submission to download at the same time"
+        )]
+        attachment_concurrency: usize,
+        #[clap(
+            long,
+            help = "Skip attachments that have already been downloaded, even if their size differs from the one on Canvas"
+        )]
+        skip_existing: bool,
+        #[clap(
+            long,
+            conflicts_with = "skip_existing",
+            help = "Download every attachment again, even if it is already present"
+        )]
+        force: bool,
+        #[clap(
+            long,
+            help = "Only download attachments with this extension, e.g. `pdf`. Can be given several times"
+        )]
+        only_ext: Vec<String>,
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = Layout::Flat,
+            help = "Whether to put all files in the folder, or one subfolder per student"
+        )]
+        layout: Layout,
+        #[clap(
+            long,
+            help = "How to name the files, using {time}, {name}, {email}, {kth_id}, {original} and {submission_id} [default: {time}-{name}-{original}, or {original} with --layout per-student]"
+        )]
+        filename_template: Option<FilenameTemplate>,
+        #[clap(
+            long,
+            default_value = "latest",
+            help = "Which attempts to download: `latest`, `all`, or an attempt number"
+        )]
+        attempt: Attempt,
+        #[clap(
+            long,
+            default_value_t = DEFAULT_FUZZY_THRESHOLD,
+            value_parser = parse_threshold,
+            alias = "name-match-threshold",
+            help = "Minimum name similarity (0.0-1.0) for matching a booking to a submission by name"
+        )]
+        match_threshold: f64,
+        #[clap(
+            long,
+            help = "A TOML file mapping booking names or emails to Canvas logins, used before automatic matching"
+        )]
+        mapping: Option<PathBuf>,
+        #[clap(
+            long,
+            help = "Match bookings to the files of their group, for group assignments"
+        )]
+        group: bool,
+        #[clap(
+            long,
+            help = "Match bookings and print the files that would be written, without downloading"
+        )]
+        dry_run: bool,
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = Format::Text,
+            help = "With `csv`, also write a summary.csv of the matches to the folder"
+        )]
+        format: Format,
+        #[clap(
+            long,
+            help = "Include the current Canvas grades in the manifest, and write a grades.csv"
+        )]
+        with_grades: bool,
+        #[clap(
+            long,
+            value_parser = parse_date,
+            visible_alias = "since",
+            help = "Only include bookings from this time, e.g. `2024-05-01` or `2024-05-01 13:00`"
+        )]
+        from: Option<DateTime<Utc>>,
+        #[clap(
+            long,
+            value_parser = parse_date,
+            help = "Only include bookings before this time"
+        )]
+        until: Option<DateTime<Utc>>,
+        #[clap(long, help = "Only download submissions made after the due date")]
+        late_only: bool,
+        #[clap(
+            long,
+            value_parser = ["submitted", "graded", "pending_review"],
+            help = "Only download submissions in this state, e.g. `submitted` for the ungraded ones. Can be given several times"
+        )]
+        state: Vec<String>,
+        #[clap(
+            long,
+            help = "Write the bookings without a submission to this file, as JSON if it ends with .json and CSV otherwise"
+        )]
+        unmatched_report: Option<PathBuf>,
+        #[clap(
+            long,
+            help = "Write a CSV of every booking, the submission it matched and how, to this file"
+        )]
+        report: Option<PathBuf>,
+        #[clap(
+            long,
+            help = "Also pack the downloaded files and the manifest into this zip archive"
+        )]
+        zip: Option<PathBuf>,
+        #[clap(
+            long,
+            help = "Extract downloaded zip archives into a folder named after the archive"
+        )]
+        zip_extract: bool,
+        #[clap(
+            long,
+            requires = "zip_extract",
+            help = "Delete the zip archives once extracted"
+        )]
+        zip_extract_delete: bool,
+        #[clap(
+            long,
+            help = "Exit with an error if any booking has no submission, not only when downloads fail"
+        )]
+        strict: bool,
+        #[clap(
+            long,
+            help = "Skip REMORES booking lists that can't be read, instead of stopping"
+        )]
+        keep_going: bool,
+        #[clap(
+            long,
+            help = "Don't show progress bars, only a line per downloaded file"
+        )]
+        no_progress: bool,
+    },
+    #[clap(
+        about = "Match bookings from REMORES to submissions on Canvas without downloading, and fail if any booking is unmatched."
+    )]
+    Check {
         #[clap(short, long, help = "The REMORES repository name")]
         repo: String,
-        #[clap(short, long, help = "Your KTH ID, eg. `asalamon`")]
-        kth_id: String,
+        #[clap(
+            short,
+            long,
+            required = true,
+            help = "Your KTH ID, eg. `asalamon`. Can be given several times"
+        )]
+        kth_id: Vec<String>,
         #[clap(short, long, help = "The Canvas course ID")]
         course: u32,
         #[clap(short, long, help = "The Canvas assignment ID")]
         assignment: u32,
+        #[clap(
+            long,
+            default_value_t = DEFAULT_FUZZY_THRESHOLD,
+            value_parser = parse_threshold,
+            alias = "name-match-threshold",
+            help = "Minimum name similarity (0.0-1.0) for matching a booking to a submission by name"
+        )]
+        match_threshold: f64,
+        #[clap(
+            long,
+            help = "A TOML file mapping booking names or emails to Canvas logins, used before automatic matching"
+        )]
+        mapping: Option<PathBuf>,
     },
     #[clap(about = "List all student name with bookings from REMORES.")]
     Bookings {
         #[clap(short, long, help = "The REMORES repository name")]
         repo: String,
-        #[clap(short, long, help = "Your KTH ID, eg. `asalamon`")]
-        kth_id: String,
+        #[clap(
+            short,
+            long,
+            required = true,
+            help = "Your KTH ID, eg. `asalamon`. Can be given several times"
+        )]
+        kth_id: Vec<String>,
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = Format::Text,
+            help = "Print the bookings as text or CSV"
+        )]
+        format: Format,
+        #[clap(long, help = "Also print the REMORES sublist each booking came from")]
+        show_events: bool,
     },
+    #[clap(
+        about = "Set grades on Canvas from a CSV with the columns login_id, grade and comment."
+    )]
+    Grade {
+        #[clap(help = "The CSV file, e.g. a grades.csv written by `download --with-grades`")]
+        file: PathBuf,
+        #[clap(short, long, help = "The Canvas course ID")]
+        course: u32,
+        #[clap(short, long, help = "The Canvas assignment ID")]
+        assignment: u32,
+    },
+    #[clap(about = "List every booking in a REMORES repository.")]
+    AllBookings {
+        #[clap(short, long, help = "The REMORES repository name")]
+        repo: String,
+    },
+    #[clap(
+        about = "Check whether REMORES repositories exist. REMORES can't list them, so you have to guess the names."
+    )]
+    Repositories {
+        #[clap(required = true, help = "The repository names to check")]
+        names: Vec<String>,
+    },
+    #[clap(
+        hide = true,
+        about = "Print a shell completion script, e.g. `remores-dl completions zsh > ~/.zfunc/_remores-dl`."
+    )]
+    Completions { shell: Shell },
+}
+
+/// A booking and the Canvas user it was matched to, printed by `check`.
+#[derive(Serialize)]
+struct CheckRow<'a> {
+    time: DateTime<Utc>,
+    name: &'a str,
+    email: String,
+    canvas_name: Option<&'a str>,
+    canvas_login: Option<&'a str>,
+    #[serde(rename = "match")]
+    method: Option<&'a MatchMethod>,
+}
+
+/// A submission, printed by `submissions`.
+#[derive(Serialize)]
+struct SubmissionRow<'a> {
+    id: u64,
+    name: &'a str,
+    login: &'a str,
+    attachments: usize,
+    /// The total size of the attachments.
+    bytes: u64,
+    workflow_state: Option<&'a str>,
+}
+
+/// A row of the CSV read by the `grade` command. Other columns are ignored.
+#[derive(Deserialize)]
+struct GradeRow {
+    login_id: String,
+    grade: String,
+    comment: Option<String>,
+}
+
+impl GradeRow {
+    /// How Canvas finds the student: by their login, which is an email at
+    /// KTH, or by the SIS id that accounts without a login are listed with.
+    fn user_reference(&self) -> String {
+        if self.login_id.contains('@') {
+            format!("sis_login_id:{}", self.login_id)
+        } else {
+            format!("sis_user_id:{}", self.login_id)
+        }
+    }
+}
+
+/// Defaults for the command line options, read from the config file.
+/// Options given on the command line or in the environment take precedence.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    canvas_api_token: Option<String>,
+    canvas_base_url: Option<String>,
+    remores_url: Option<String>,
+    repo: Option<String>,
+    kth_id: Vec<String>,
+    course: Option<u32>,
+    assignment: Option<u32>,
+    /// The folder that relative download folders are in.
+    base_dir: Option<String>,
+    /// How many submissions to download at the same time.
+    concurrency: Option<usize>,
+    match_threshold: Option<f64>,
+    timezone: Option<String>,
+}
+
+impl Config {
+    /// Reads the config file at `path`. Without one, reads the user config
+    /// file with the config file in the current folder on top of it.
+    /// Without any config file every option has its usual default.
+    fn read(path: Option<&Path>) -> Result<Config, anyhow::Error> {
+        if let Some(path) = path {
+            return Config::read_file(path);
+        }
+        let mut config = match user_config_path() {
+            Some(path) if path.exists() => Config::read_file(&path)?,
+            _ => Config::default(),
+        };
+        let local = Path::new(LOCAL_CONFIG);
+        if local.exists() {
+            config = config.overlay(Config::read_local(local)?);
+        }
+        Ok(config)
+    }
+
+    fn read_file(path: &Path) -> Result<Config, anyhow::Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read config {}: {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// Reads the config file in the current folder. It can come with a
+    /// checkout of someone else's project, so it may not set the Canvas
+    /// token, or the server the token is sent to.
+    fn read_local(path: &Path) -> Result<Config, anyhow::Error> {
+        let config = Config::read_file(path)?;
+        if config.canvas_api_token.is_some() || config.canvas_base_url.is_some() {
+            anyhow::bail!(
+                "{} may not set canvas_api_token or canvas_base_url, put them in the user config file or give them on the command line",
+                path.display()
+            );
+        }
+        Ok(config)
+    }
+
+    /// These options, with the ones set in `other` taking precedence.
+    fn overlay(self, other: Config) -> Config {
+        Config {
+            canvas_api_token: other.canvas_api_token.or(self.canvas_api_token),
+            canvas_base_url: other.canvas_base_url.or(self.canvas_base_url),
+            remores_url: other.remores_url.or(self.remores_url),
+            repo: other.repo.or(self.repo),
+            kth_id: if other.kth_id.is_empty() {
+                self.kth_id
+            } else {
+                other.kth_id
+            },
+            course: other.course.or(self.course),
+            assignment: other.assignment.or(self.assignment),
+            base_dir: other.base_dir.or(self.base_dir),
+            concurrency: other.concurrency.or(self.concurrency),
+            match_threshold: other.match_threshold.or(self.match_threshold),
+            timezone: other.timezone.or(self.timezone),
+        }
+    }
+
+    /// Sets the values of the config file as the defaults of the matching
+    /// arguments of `command` and its subcommands.
+    fn apply(&self, command: clap::Command) -> clap::Command {
+        let mut command = command;
+        let mut defaults = vec![];
+        if let Some(token) = &self.canvas_api_token {
+            defaults.push(("canvas_api_token", vec![token.clone()]));
+        }
+        if let Some(url) = &self.canvas_base_url {
+            defaults.push(("canvas_base_url", vec![url.clone()]));
+        }
+        if let Some(url) = &self.remores_url {
+            defaults.push(("remores_url", vec![url.clone()]));
+        }
+        if let Some(repo) = &self.repo {
+            defaults.push(("repo", vec![repo.clone()]));
+        }
+        if !self.kth_id.is_empty() {
+            defaults.push(("kth_id", self.kth_id.clone()));
+        }
+        if let Some(course) = self.course {
+            defaults.push(("course", vec![course.to_string()]));
+        }
+        if let Some(assignment) = self.assignment {
+            defaults.push(("assignment", vec![assignment.to_string()]));
+        }
+        if let Some(base_dir) = &self.base_dir {
+            defaults.push(("base_dir", vec![base_dir.clone()]));
+        }
+        if let Some(concurrency) = self.concurrency {
+            defaults.push(("concurrency", vec![concurrency.to_string()]));
+        }
+        if let Some(threshold) = self.match_threshold {
+            defaults.push(("match_threshold", vec![threshold.to_string()]));
+        }
+        if let Some(timezone) = &self.timezone {
+            defaults.push(("timezone", vec![timezone.clone()]));
+        }
+
+        for (id, values) in defaults {
+            command = with_default(command, id, &values);
+        }
+        // Keep the token out of --help.
+        command.mut_arg("canvas_api_token", |arg| arg.hide_default_value(true))
+    }
+}
+
+/// Sets `values` as the default of every argument `id` in `command` and its
+/// subcommands, which are then no longer required.
+fn with_default(command: clap::Command, id: &str, values: &[String]) -> clap::Command {
+    let mut command = command;
+    if command.get_arguments().any(|arg| arg.get_id() == id) {
+        command = command.mut_arg(id, |arg| {
+            arg.required(false)
+                .required_unless_present(clap::builder::Resettable::Reset)
+                .default_values(
+                    values
+                        .iter()
+                        .map(|value| clap::builder::OsStr::from(value.clone())),
+                )
+        });
+    }
+    let names: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    for name in names {
+        command = command.mut_subcommand(name, |subcommand| with_default(subcommand, id, values));
+    }
+    command
+}
+
+/// The config file in the current folder, e.g. with the course of a
+/// project. Its options take precedence over the user config file.
+const LOCAL_CONFIG: &str = "remores-dl.toml";
+
+/// `$XDG_CONFIG_HOME/remores-dl/config.toml` on Linux, or the equivalent on
+/// other systems.
+fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("remores-dl").join("config.toml"))
+}
+
+/// Finds `--config` among the arguments, which is needed before they can
+/// be parsed with the defaults from the config file.
+fn config_arg() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
 }
 
 #[derive(Parser)]
@@ -42,121 +605,1056 @@ struct Cli {
         env,
         help = "Can be obtained from https://canvas.kth.se/profile/settings"
     )]
-    canvas_api_token: String,
+    canvas_api_token: Option<String>,
+    #[clap(
+        long,
+        env,
+        default_value = DEFAULT_API_URL,
+        alias = "canvas-url",
+        help = "Base URL of the Canvas API"
+    )]
+    canvas_base_url: String,
+    #[clap(
+        long,
+        default_value_t = DEFAULT_TIMEOUT.as_secs(),
+        help = "Seconds to wait for Canvas or REMORES before giving up on a request"
+    )]
+    timeout: u64,
+    #[clap(
+        long,
+        default_value_t = DEFAULT_MAX_RETRIES,
+        help = "How many times to retry a Canvas request that failed with a server error, rate limiting or a timeout"
+    )]
+    max_retries: u32,
+    #[clap(
+        long,
+        env,
+        default_value = DEFAULT_REMORES_URL,
+        help = "URL of the REMORES decoder"
+    )]
+    remores_url: String,
+    #[clap(
+        long,
+        default_value_t = DEFAULT_CONCURRENCY,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "How many REMORES booking lists to fetch at the same time"
+    )]
+    remores_concurrency: usize,
+    #[clap(
+        long,
+        global = true,
+        help = "Print listings, like courses or bookings, as JSON"
+    )]
+    json: bool,
+    #[clap(
+        long,
+        global = true,
+        default_value_t = TIMEZONE,
+        help = "Time zone to show booking times and name files in, e.g. Europe/London"
+    )]
+    timezone: Tz,
+    #[clap(
+        long,
+        help = "Always fetch fresh pages from REMORES instead of using the cache"
+    )]
+    no_cache: bool,
+    #[clap(
+        long,
+        help = "Where to cache REMORES pages [default: the user cache folder]"
+    )]
+    cache_dir: Option<PathBuf>,
+    #[clap(
+        short,
+        long,
+        global = true,
+        action = clap::ArgAction::Count,
+        help = "Log more about requests to Canvas and REMORES, -vv for debug logs"
+    )]
+    verbose: u8,
+    #[clap(
+        long,
+        global = true,
+        help = "Read Canvas and REMORES responses from this folder instead of the network"
+    )]
+    fixtures: Option<PathBuf>,
+    #[clap(
+        short,
+        long,
+        global = true,
+        conflicts_with = "verbose",
+        help = "Only print results, warnings and errors, without progress"
+    )]
+    quiet: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Read default options from this file only [default: remores-dl/config.toml in the user config folder, e.g. ~/.config on Linux, with ./remores-dl.toml on top]"
+    )]
+    config: Option<PathBuf>,
     #[clap(subcommand)]
     command: Option<Commands>,
 }
 
+fn parse_threshold(value: &str) -> Result<f64, String> {
+    let threshold: f64 = value
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", value))?;
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(format!("{} is not between 0.0 and 1.0", threshold));
+    }
+    Ok(threshold)
+}
+
+/// Parses a date given on the command line, either as RFC 3339 or as a
+/// Stockholm local date with an optional time, e.g. `2024-05-01 13:00`.
+fn parse_date(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let local = ["%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| format!("`{}` is not a date like `2024-05-01 13:00`", value))?;
+
+    TIMEZONE
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("`{}` does not exist in Stockholm time", value))
+}
+
+/// Creates a Canvas client from the command line options, and checks that
+/// the API token is valid before doing anything else.
+async fn connect_canvas(cli: &Cli) -> Result<Canvas, anyhow::Error> {
+    let token = match (&cli.canvas_api_token, &cli.fixtures) {
+        (Some(token), _) => token.clone(),
+        (None, Some(_)) => String::new(),
+        (None, None) => anyhow::bail!(
+            "A Canvas API token is needed, give it with --canvas-api-token or CANVAS_API_TOKEN"
+        ),
+    };
+    let mut canvas = Canvas::with_base_url(token, cli.canvas_base_url.clone())?
+        .with_timeout(Duration::from_secs(cli.timeout))
+        .with_retries(cli.max_retries, DEFAULT_RETRY_BASE_DELAY);
+    if let Some(dir) = &cli.fixtures {
+        canvas = canvas.with_fixtures(dir.clone());
+    }
+    canvas.verify_token().await?;
+    Ok(canvas)
+}
+
+/// Creates a REMORES client for `repo` from the command line options.
+fn connect_remores(cli: &Cli, repo: &str) -> Remores {
+    let remores = Remores::with_url(repo.to_string(), cli.remores_url.clone())
+        .with_timeout(Duration::from_secs(cli.timeout))
+        .with_concurrency(cli.remores_concurrency);
+    if let Some(dir) = &cli.fixtures {
+        return remores.with_fixtures(dir.clone());
+    }
+    if cli.no_cache {
+        return remores;
+    }
+
+    match cli.cache_dir.clone().or_else(default_cache_dir) {
+        Some(dir) => remores.with_cache(dir, DEFAULT_CACHE_TTL),
+        None => remores,
+    }
+}
+
+/// Lets the user pick one of their courses on Canvas, for `--interactive`.
+async fn pick_course(canvas: &Canvas) -> Result<u32, anyhow::Error> {
+    let courses = canvas.get_courses().await?;
+    if courses.is_empty() {
+        anyhow::bail!("You are not a teacher or TA in any course on Canvas");
+    }
+
+    let items: Vec<String> = courses
+        .iter()
+        .map(|course| format!("{}: {}", course.id, course.name))
+        .collect();
+    let index = Select::new()
+        .with_prompt("Course")
+        .items(&items)
+        .default(0)
+        .interact()?;
+    Ok(u32::try_from(courses[index].id)?)
+}
+
+/// Lets the user pick one of the assignments in `course`, for
+/// `--interactive`.
+async fn pick_assignment(canvas: &Canvas, course: &u32) -> Result<u32, anyhow::Error> {
+    let assignments = canvas
+        .get_assignments(&course.to_string(), &AssignmentOptions::default())
+        .await?;
+    if assignments.is_empty() {
+        anyhow::bail!("Course {} has no assignments", course);
+    }
+
+    let items: Vec<String> = assignments
+        .iter()
+        .map(|assignment| format!("{}: {}", assignment.id, assignment.name))
+        .collect();
+    let index = Select::new()
+        .with_prompt("Assignment")
+        .items(&items)
+        .default(0)
+        .interact()?;
+    Ok(u32::try_from(assignments[index].id)?)
+}
+
+/// Replaces a leading `~` in `path` with the home folder.
+fn expand_tilde(path: &str) -> PathBuf {
+    let rest = match path.strip_prefix('~') {
+        Some("") => "",
+        Some(rest) if rest.starts_with(std::path::is_separator) => &rest[1..],
+        _ => return PathBuf::from(path),
+    };
+    match dirs::home_dir() {
+        Some(home) => home.join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+/// The folder to download to: `folder` itself if it is absolute, otherwise
+/// `folder` in `base_dir`, if there is one.
+fn download_folder(folder: &str, base_dir: Option<&str>) -> PathBuf {
+    let folder = expand_tilde(folder);
+    match base_dir {
+        Some(base_dir) if folder.is_relative() => expand_tilde(base_dir).join(folder),
+        _ => folder,
+    }
+}
+
+/// Formats a number of seconds as e.g. `2d 3h`, `3h 12m` or `5m`.
+fn format_lateness(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes.max(1))
+    }
+}
+
+/// Formats a number of bytes as e.g. `512 B`, `3.4 MiB` or `1.3 GiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Prints `warning` to stderr, so that it stays apart from the results.
+fn print_warning(warning: &Warning, timezone: Tz) {
+    match warning {
+        Warning::SkippedSublist { sub_list, reason } => eprintln!(
+            "[Warn]: Skipped booking list {}, its bookings are missing: {}",
+            sub_list, reason
+        ),
+        Warning::Unmatched { booking, closest } => {
+            eprintln!(
+                "[Warn]: No submission found for booking: {}, {} @ {}",
+                booking.name,
+                booking.email,
+                booking.time.with_timezone(&timezone)
+            );
+            if let Some((user, score)) = closest {
+                eprintln!(
+                    "[Warn]: The most similar name was {} (similarity {:.2})",
+                    user, score
+                );
+            }
+        }
+        Warning::NotSubmitted { booking, user } => eprintln!(
+            "[Warn]: {} has not submitted, for booking @ {}",
+            user,
+            booking.time.with_timezone(&timezone)
+        ),
+        Warning::FuzzyMatch {
+            booking,
+            user,
+            score,
+            ambiguous,
+        } => {
+            eprintln!(
+                "[Match]: Booking {} matched to {} by name (similarity {:.2})",
+                booking.name, user, score
+            );
+            if *ambiguous {
+                eprintln!(
+                    "[Ambiguous]: Another submission was almost as similar to {}, please verify",
+                    booking.name
+                );
+            }
+        }
+        Warning::Duplicate {
+            user,
+            bookings,
+            time,
+        } => eprintln!(
+            "[Duplicate]: Submission by {} matched {} bookings, downloading it once for the booking @ {}",
+            user,
+            bookings,
+            time.with_timezone(&timezone)
+        ),
+        Warning::Late { user, seconds_late } => eprintln!(
+            "[Late]: Submission by {} was {} late",
+            user,
+            format_lateness(*seconds_late)
+        ),
+        Warning::EmptyAttachment { user, name } => {
+            eprintln!("[Warn]: {} submitted by {} is an empty file", name, user)
+        }
+    }
+}
+
+fn print_summary(summary: &DownloadSummary, dry_run: bool) {
+    println!(
+        "{} bookings, {} matched ({} exact / {} fuzzy), {} unmatched, {} files {}, {} failures",
+        summary.bookings,
+        summary.matched(),
+        summary.exact,
+        summary.fuzzy,
+        summary.unmatched,
+        summary.files,
+        if dry_run { "planned" } else { "written" },
+        summary.failures
+    );
+    if summary.skipped > 0 {
+        println!(
+            "{} files already present, not downloaded again",
+            summary.skipped
+        );
+    }
+    if summary.excluded > 0 {
+        println!(
+            "{} attachments skipped because of their extension",
+            summary.excluded
+        );
+    }
+    if !dry_run {
+        println!(
+            "Downloaded {} across {} files in {:.1}s",
+            format_bytes(summary.bytes),
+            summary.files,
+            summary.elapsed.as_secs_f64()
+        );
+    }
+}
+
+/// Creates a progress bar for `len` submissions.
+fn progress_bar(len: u64) -> ProgressBar {
+    ProgressBar::new(len).with_style(
+        ProgressStyle::with_template("{bar:40} {pos} of {len} submissions downloaded").unwrap(),
+    )
+}
+
+/// Shows a bar with the bytes of each attachment being downloaded, below
+/// the bar of submissions.
+struct FileBars {
+    multi: MultiProgress,
+    bars: Mutex<HashMap<PathBuf, ProgressBar>>,
+}
+
+impl DownloadProgress for FileBars {
+    fn start(&self, path: &Path, len: Option<u64>) {
+        let bar = match len {
+            Some(len) => ProgressBar::new(len).with_style(
+                ProgressStyle::with_template(
+                    "  {bar:30} {bytes:>10} of {total_bytes:<10} {wide_msg}",
+                )
+                .unwrap(),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("  {spinner} {bytes:>10} {wide_msg}").unwrap(),
+            ),
+        };
+        let name = path.file_name().unwrap_or_default();
+        bar.set_message(name.to_string_lossy().into_owned());
+        let bar = self.multi.add(bar);
+        self.bars.lock().unwrap().insert(path.to_path_buf(), bar);
+    }
+
+    fn advance(&self, path: &Path, bytes: u64) {
+        if let Some(bar) = self.bars.lock().unwrap().get(path) {
+            bar.inc(bytes);
+        }
+    }
+
+    fn finish(&self, path: &Path) {
+        if let Some(bar) = self.bars.lock().unwrap().remove(path) {
+            bar.finish_and_clear();
+            self.multi.remove(&bar);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let cli = Cli::parse();
+    let config = Config::read(config_arg().as_deref())?;
+    let matches = config.apply(Cli::command()).get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    let level = match cli.verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
 
     match &cli.command {
         Some(Commands::Courses) => {
-            let client = Canvas::new(cli.canvas_api_token);
-            println!("Finding courses on Canvas...");
+            let client = connect_canvas(&cli).await?;
+            if !cli.json {
+                status!(cli, "Finding courses on Canvas...");
+            }
 
             let courses = client.get_courses().await?;
 
-            println!("Available courses:");
-            for course in courses {
-                println!("  {}: {}", course.id, course.name);
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&courses)?);
+            } else {
+                println!("Available courses:");
+                for course in courses {
+                    println!("  {}: {}", course.id, course.name);
+                }
             }
         }
-        Some(Commands::Assignments { course_id }) => {
-            let client = Canvas::new(cli.canvas_api_token);
-            println!("Finding assignments for course {} on Canvas...", course_id);
+        Some(Commands::Assignments {
+            course_id,
+            after,
+            before,
+            include_undated,
+            include_unpublished,
+            all_grading_types,
+        }) => {
+            let client = connect_canvas(&cli).await?;
+            if !cli.json {
+                status!(
+                    cli,
+                    "Finding assignments for course {} on Canvas...",
+                    course_id
+                );
+            }
 
-            let assignments = client.get_assignments(course_id).await?;
+            let mut assignments = client
+                .get_assignments(
+                    course_id,
+                    &AssignmentOptions {
+                        include_unpublished: *include_unpublished,
+                        all_grading_types: *all_grading_types,
+                    },
+                )
+                .await?;
+            if after.is_some() || before.is_some() {
+                assignments.retain(|a| match a.due_at() {
+                    Some(due) => {
+                        after.is_none_or(|after| due >= after)
+                            && before.is_none_or(|before| due < before)
+                    }
+                    None => *include_undated,
+                });
+            }
 
-            println!("Available assignments:");
-            for assignment in assignments {
-                println!("  {}: {}", assignment.id, assignment.name);
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&assignments)?);
+            } else {
+                println!("Available assignments:");
+                for assignment in assignments {
+                    println!("  {}: {}", assignment.id, assignment.name);
+                }
+            }
+        }
+        Some(Commands::Students { course }) => {
+            let client = connect_canvas(&cli).await?;
+            if !cli.json {
+                status!(cli, "Finding students in course {} on Canvas...", course);
+            }
+
+            let students = client.get_students(course).await?;
+
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&students)?);
+            } else {
+                println!("Found {} students:", students.len());
+                for student in students {
+                    println!("  {}: {}", student.identifier(), student.name);
+                }
+            }
+        }
+        Some(Commands::Submissions {
+            course,
+            assignment,
+            all,
+            state,
+        }) => {
+            let client = connect_canvas(&cli).await?;
+            if !cli.json {
+                status!(
+                    cli,
+                    "Finding submissions for assignment {} in course {} on Canvas...",
+                    assignment,
+                    course
+                );
+            }
+
+            let mut submissions = client
+                .get_submissions(course, assignment, &SubmissionIncludes::default())
+                .await?;
+            if !all && !state.iter().any(|s| s == "unsubmitted") {
+                submissions.retain(Submission::is_submitted);
+            }
+            submissions.retain(|submission| submission.in_states(state));
+            submissions.sort_by(|a, b| a.user.name.cmp(&b.user.name));
+            let rows: Vec<SubmissionRow> = submissions
+                .iter()
+                .map(|submission| SubmissionRow {
+                    id: submission.id,
+                    name: &submission.user.name,
+                    login: submission.user.identifier(),
+                    attachments: submission.attachments().len(),
+                    bytes: submission
+                        .attachments()
+                        .iter()
+                        .filter_map(|attachment| attachment.size())
+                        .sum(),
+                    workflow_state: submission.workflow_state.as_deref(),
+                })
+                .collect();
+
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&rows)?);
+            } else {
+                println!("Found {} submissions:", rows.len());
+                for row in rows {
+                    println!(
+                        "  {}: {} ({}), {}, attachments: {} ({})",
+                        row.id,
+                        row.name,
+                        row.login,
+                        row.workflow_state.unwrap_or("unknown"),
+                        row.attachments,
+                        format_bytes(row.bytes)
+                    );
+                }
             }
         }
         Some(Commands::Download {
             kth_id,
             folder,
+            base_dir,
+            interactive,
             repo,
             course,
             assignment,
+            concurrency,
+            attachment_concurrency,
+            skip_existing,
+            force,
+            only_ext,
+            layout,
+            filename_template,
+            attempt,
+            match_threshold,
+            mapping,
+            group,
+            dry_run,
+            format,
+            with_grades,
+            from,
+            until,
+            late_only,
+            state,
+            unmatched_report,
+            report: match_report,
+            zip,
+            zip_extract,
+            zip_extract_delete,
+            strict,
+            keep_going,
+            no_progress,
         }) => {
-            println!("Finding bookings for {} on REMORES...", repo);
-            let remores: Remores = Remores::new(repo.to_string());
-            let bookings = remores.get_bookings_for(kth_id.to_string()).await?;
+            if *layout == Layout::Flat
+                && filename_template
+                    .as_ref()
+                    .is_some_and(|template| !template.identifies_student())
+            {
+                anyhow::bail!(
+                    "With --layout flat, --filename-template needs {{name}}, {{email}}, {{kth_id}} or {{submission_id}}, or files of different students get the same name"
+                );
+            }
+            let mapping = match mapping {
+                Some(path) => read_mapping(path)?,
+                None => HashMap::new(),
+            };
 
-            println!("Found {} bookings", bookings.len());
+            let canvas = connect_canvas(&cli).await?;
 
-            println!(
-                "Finding submissions assignment {} in course {} on Canvas...",
-                assignment, course
-            );
-            let canvas = Canvas::new(cli.canvas_api_token);
-            let bookings_with_submissions = canvas
-                .get_assignment_submissions(course, assignment, &bookings)
-                .await?;
+            if *interactive && !std::io::stdin().is_terminal() {
+                anyhow::bail!("--interactive needs a terminal to ask in");
+            }
+            // Without --interactive, clap makes sure these are all given.
+            let course = &match course {
+                Some(course) => *course,
+                None => pick_course(&canvas).await?,
+            };
+            let assignment = &match assignment {
+                Some(assignment) => *assignment,
+                None => pick_assignment(&canvas, course).await?,
+            };
+            let repo = &match repo {
+                Some(repo) => repo.clone(),
+                None => Input::new()
+                    .with_prompt("REMORES repository")
+                    .interact_text()?,
+            };
+            let kth_id = &match kth_id.is_empty() {
+                false => kth_id.clone(),
+                true => Input::<String>::new()
+                    .with_prompt("Your KTH IDs, separated by spaces")
+                    .interact_text()?
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect(),
+            };
 
-            let n_bookings_with_submissions = bookings_with_submissions
-                .iter()
-                .filter(|(_, submission)| submission.is_some())
-                .count();
-            println!(
-                "Found matching submissions for {} bookings",
-                n_bookings_with_submissions
+            let course_info = canvas.get_course(course).await?;
+            let assignment_info = canvas.get_assignment(course, assignment).await?;
+            status!(
+                cli,
+                "Downloading {} in {}",
+                assignment_info.name,
+                course_info.name
             );
-
-            for (booking, _) in bookings_with_submissions
-                .iter()
-                .filter(|(_, submission)| submission.is_none())
-            {
-                println!(
-                    "[Warn]: No submission found for booking: {}, {} @ {}",
-                    booking.name, booking.email, booking.time
+            if assignment_info.is_group_assignment() && !*group {
+                status!(
+                    cli,
+                    "[Info]: {} is a group assignment, consider using --group",
+                    assignment_info.name
                 );
             }
 
-            println!("Downloading submissions to {}...", folder);
+            status!(cli, "Finding bookings for {} on REMORES...", repo);
+            let remores = connect_remores(&cli, repo);
 
-            let folder = Path::new(folder);
-            fs::create_dir_all(folder)?;
+            let folder = download_folder(folder, base_dir.as_deref());
+            let folder = folder.as_path();
+            let options = RunOptions {
+                kth_ids: kth_id.clone(),
+                course: *course,
+                assignment: *assignment,
+                folder: folder.to_path_buf(),
+                matching: MatchOptions {
+                    fuzzy_threshold: *match_threshold,
+                    mapping,
+                    group: *group,
+                    include: SubmissionIncludes {
+                        submission_history: *attempt != Attempt::Latest,
+                        ..Default::default()
+                    },
+                },
+                download: DownloadOptions {
+                    layout: *layout,
+                    filename_template: filename_template.clone(),
+                    attempt: *attempt,
+                    skip_existing: *skip_existing,
+                    force: *force,
+                    only_ext: only_ext.clone(),
+                    attachment_concurrency: *attachment_concurrency,
+                    timezone: Some(cli.timezone),
+                    extract_zips: *zip_extract,
+                    delete_extracted_zips: *zip_extract_delete,
+                },
+                concurrency: *concurrency,
+                from: *from,
+                until: *until,
+                late_only: *late_only,
+                states: state.clone(),
+                dry_run: *dry_run,
+                keep_going: *keep_going,
+            };
 
-            for (booking, submission) in bookings_with_submissions {
-                if let Some(submission) = submission {
-                    let file_name = format!(
-                        "{}-{}",
-                        booking.time.format("%Y%m%d%H%M"),
-                        submission.user.name
+            // Bars are hidden when stdout is not a terminal, so that piped
+            // output stays clean.
+            let show_progress = !cli.quiet && !*no_progress && std::io::stdout().is_terminal();
+            let multi = MultiProgress::with_draw_target(if show_progress {
+                ProgressDrawTarget::stdout()
+            } else {
+                ProgressDrawTarget::hidden()
+            });
+            let canvas = if show_progress {
+                canvas.with_download_progress(Arc::new(FileBars {
+                    multi: multi.clone(),
+                    bars: Mutex::new(HashMap::new()),
+                }))
+            } else {
+                canvas
+            };
+
+            let mut progress = ProgressBar::hidden();
+            let report = run_download(&canvas, &remores, &options, |event| match event {
+                Progress::Bookings(n) => {
+                    status!(cli, "Found {} bookings", n);
+                    status!(
+                        cli,
+                        "Finding submissions assignment {} in course {} on Canvas...",
+                        assignment,
+                        course
                     );
-                    match canvas
-                        .download_submission(&submission, folder, file_name.as_str())
-                        .await
-                    {
-                        Ok(paths) => {
-                            for path in paths {
-                                println!("Downloaded submission to {}", path.display());
+                }
+                Progress::Matched(n) => status!(
+                    cli,
+                    "Found matching submissions for {} bookings (name similarity threshold {:.2})",
+                    n,
+                    match_threshold
+                ),
+                Progress::Warning(warning) => {
+                    multi.suspend(|| print_warning(warning, cli.timezone))
+                }
+                Progress::Downloading(n) => {
+                    status!(cli, "Downloading submissions to {}...", folder.display());
+                    progress = multi.add(progress_bar(n as u64));
+                }
+                Progress::Downloaded(submission, result) => {
+                    multi.suspend(|| match result {
+                        Ok(files) => {
+                            for file in files {
+                                if file.skipped {
+                                    status!(
+                                        cli,
+                                        "Skipped {}, already present",
+                                        file.path.display()
+                                    );
+                                } else {
+                                    status!(
+                                        cli,
+                                        "Downloaded submission to {}",
+                                        file.path.display()
+                                    );
+                                }
                             }
                         }
                         Err(e) => {
                             eprintln!("Failed to download submission {}: {}", submission.user, e)
                         }
+                    });
+                    progress.inc(1);
+                }
+            })
+            .await?;
+            progress.finish();
+
+            if let Some(path) = unmatched_report {
+                report.write_unmatched(path)?;
+                status!(cli, "Wrote unmatched bookings to {}", path.display());
+            }
+            if let Some(path) = match_report {
+                report.manifest(folder, false).write_csv(path)?;
+                status!(cli, "Wrote match report to {}", path.display());
+            }
+
+            if *dry_run {
+                let mut shown = HashSet::new();
+                for m in report.matches.values().flatten() {
+                    if !shown.insert(m.submission.id) {
+                        continue;
                     }
+                    for path in report.files.get(&m.submission.id).into_iter().flatten() {
+                        if report.skipped.contains(path) {
+                            println!("Would skip {}, already present", path.display())
+                        } else if path.exists() {
+                            println!(
+                                "Would overwrite {} with submission {}",
+                                path.display(),
+                                m.submission.user
+                            )
+                        } else {
+                            println!(
+                                "Would download submission {} to {}",
+                                m.submission.user,
+                                path.display()
+                            )
+                        }
+                    }
+                }
+            } else {
+                let manifest = report.manifest(folder, *with_grades);
+                let manifest_path = folder.join("manifest.json");
+                manifest.write(&manifest_path)?;
+                status!(cli, "Wrote manifest to {}", manifest_path.display());
+
+                if *format == Format::Csv {
+                    let summary_path = folder.join("summary.csv");
+                    manifest.write_csv(&summary_path)?;
+                    status!(cli, "Wrote summary to {}", summary_path.display());
+                }
+
+                if *with_grades {
+                    let grades_path = folder.join("grades.csv");
+                    manifest.write_grades_csv(&grades_path)?;
+                    status!(cli, "Wrote grades to {}", grades_path.display());
+                }
+
+                if let Some(zip_path) = zip {
+                    manifest.write_zip(zip_path, folder)?;
+                    status!(cli, "Wrote archive to {}", zip_path.display());
                 }
             }
+
+            let summary = report.summary();
+            print_summary(&summary, *dry_run);
+            if summary.failures > 0 {
+                anyhow::bail!("Failed to download {} submissions", summary.failures);
+            }
+            if *strict {
+                report.require_matched()?;
+            }
         }
-        Some(Commands::Bookings { repo, kth_id }) => {
-            let remores = Remores::new(repo.to_string());
+        Some(Commands::Check {
+            repo,
+            kth_id,
+            course,
+            assignment,
+            match_threshold,
+            mapping,
+        }) => {
+            let mapping = match mapping {
+                Some(path) => read_mapping(path)?,
+                None => HashMap::new(),
+            };
+            let canvas = connect_canvas(&cli).await?;
+            let remores = connect_remores(&cli, repo);
+
+            if !cli.json {
+                status!(cli, "Finding bookings for {} on REMORES...", repo);
+            }
+            let bookings = remores.get_bookings_for_many(kth_id).await?;
+            if !cli.json {
+                status!(
+                    cli,
+                    "Matching {} bookings to submissions on Canvas...",
+                    bookings.len()
+                );
+            }
+            let matches = canvas
+                .get_assignment_submissions(
+                    course,
+                    assignment,
+                    &bookings,
+                    &MatchOptions {
+                        fuzzy_threshold: *match_threshold,
+                        mapping,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+
+            let mut rows: Vec<CheckRow> = matches
+                .iter()
+                .map(|(booking, m)| CheckRow {
+                    time: booking.time,
+                    name: &booking.name,
+                    email: booking.email.to_string(),
+                    canvas_name: m.as_ref().map(|m| m.submission.user.name.as_str()),
+                    canvas_login: m.as_ref().map(|m| m.submission.user.identifier()),
+                    method: m.as_ref().map(|m| &m.method),
+                })
+                .collect();
+            rows.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.name.cmp(b.name)));
+            let unmatched = rows.iter().filter(|row| row.method.is_none()).count();
 
-            println!("Finding bookings for {} on REMORES...", repo);
-            let bookings = remores.get_bookings_for(kth_id.to_string()).await?;
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&rows)?);
+            } else {
+                for row in &rows {
+                    let matched = match (row.canvas_name, row.canvas_login, row.method) {
+                        (Some(name), Some(login), Some(method)) => {
+                            let method = match method {
+                                MatchMethod::Manual => "mapping".to_string(),
+                                MatchMethod::Email => "exact email".to_string(),
+                                MatchMethod::LocalPart => "local part".to_string(),
+                                MatchMethod::Fuzzy { score, .. } => format!("fuzzy {:.2}", score),
+                            };
+                            format!("{} ({}), {}", name, login, method)
+                        }
+                        _ => "UNMATCHED".to_string(),
+                    };
+                    println!(
+                        "  {}  {:<30} {:<30} {}",
+                        row.time
+                            .with_timezone(&cli.timezone)
+                            .format("%Y-%m-%d %H:%M"),
+                        row.name,
+                        row.email,
+                        matched
+                    );
+                }
+            }
+
+            if unmatched > 0 {
+                anyhow::bail!("No submission found for {} bookings", unmatched);
+            }
+        }
+        Some(Commands::Bookings {
+            repo,
+            kth_id,
+            format,
+            show_events,
+        }) => {
+            let remores = connect_remores(&cli, repo);
+
+            if !cli.json && *format == Format::Text {
+                status!(cli, "Finding bookings for {} on REMORES...", repo);
+            }
+            let bookings = remores.get_bookings_for_many(kth_id).await?;
+
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&bookings)?);
+                return Ok(());
+            }
+
+            if *format == Format::Csv {
+                let mut writer = csv::Writer::from_writer(std::io::stdout());
+                writer.write_record(["time", "name", "email", "email_kind", "event"])?;
+                for booking in &bookings {
+                    writer.write_record([
+                        booking.time.to_rfc3339().as_str(),
+                        booking.name.as_str(),
+                        booking.email.to_string().as_str(),
+                        booking.email.kind(),
+                        booking.event.as_str(),
+                    ])?;
+                }
+                writer.flush()?;
+                return Ok(());
+            }
 
             let names = bookings
                 .iter()
-                .map(|booking| format!("\"{}\"", booking.name))
+                .map(|booking| {
+                    if *show_events {
+                        format!("\"{}\" ({})", booking.name, booking.event)
+                    } else {
+                        format!("\"{}\"", booking.name)
+                    }
+                })
                 .collect::<Vec<String>>()
                 .join(",");
 
             println!("Found {} bookings:", bookings.len());
-            print!("{}\n", names);
+            println!("{}", names);
+        }
+        Some(Commands::Grade {
+            file,
+            course,
+            assignment,
+        }) => {
+            let canvas = connect_canvas(&cli).await?;
+
+            let mut reader = csv::Reader::from_path(file)?;
+            let mut failures = 0;
+            for row in reader.deserialize() {
+                let row: GradeRow = row?;
+                if row.grade.trim().is_empty() {
+                    continue;
+                }
+
+                let comment = row.comment.as_deref().filter(|c| !c.trim().is_empty());
+                match canvas
+                    .submit_grade(
+                        course,
+                        assignment,
+                        &row.user_reference(),
+                        &row.grade,
+                        comment,
+                    )
+                    .await
+                {
+                    Ok(()) => status!(cli, "Graded {}: {}", row.login_id, row.grade),
+                    Err(e) => {
+                        eprintln!("Failed to grade {}: {}", row.login_id, e);
+                        failures += 1;
+                    }
+                }
+            }
+
+            if failures > 0 {
+                anyhow::bail!("Failed to set {} grades", failures);
+            }
+        }
+        Some(Commands::AllBookings { repo }) => {
+            let remores = connect_remores(&cli, repo);
+
+            if !cli.json {
+                status!(cli, "Finding all bookings in {} on REMORES...", repo);
+            }
+            let mut bookings = remores.get_all_bookings().await?;
+            bookings.sort_by_key(|booking| booking.time);
+
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&bookings)?);
+                return Ok(());
+            }
+
+            println!("Found {} bookings:", bookings.len());
+            for booking in bookings {
+                println!(
+                    "  {}: {} ({})",
+                    booking
+                        .time
+                        .with_timezone(&cli.timezone)
+                        .format("%Y-%m-%d %H:%M"),
+                    booking.name,
+                    booking.email
+                );
+            }
+        }
+        Some(Commands::Repositories { names }) => {
+            let mut results = vec![];
+            for name in names {
+                let exists = connect_remores(&cli, name).repository_exists().await?;
+                results.push((name, exists));
+            }
+
+            if cli.json {
+                let results: HashMap<_, _> = results.into_iter().collect();
+                println!("{}", serde_json::to_string_pretty(&results)?);
+                return Ok(());
+            }
+
+            for (name, exists) in results {
+                match exists {
+                    true => println!("  {}: found", name),
+                    false => println!("  {}: not found", name),
+                }
+            }
+        }
+        Some(Commands::Completions { shell }) => {
+            clap_complete::generate(
+                *shell,
+                &mut Cli::command(),
+                env!("CARGO_BIN_NAME"),
+                &mut std::io::stdout(),
+            );
         }
         None => {
             eprintln!("No command provided");
@@ -165,3 +1663,61 @@ async fn main() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grade_rows_refer_to_logins_or_sis_ids() {
+        let row = |login_id: &str| GradeRow {
+            login_id: login_id.to_string(),
+            grade: "P".to_string(),
+            comment: None,
+        };
+        assert_eq!(
+            row("aberg@kth.se").user_reference(),
+            "sis_login_id:aberg@kth.se"
+        );
+        assert_eq!(row("u1test").user_reference(), "sis_user_id:u1test");
+    }
+
+    #[test]
+    fn parse_date_handles_daylight_saving_changes() {
+        // 02:30 happens twice on 2024-10-27, the earlier one (CEST) is used.
+        assert_eq!(
+            parse_date("2024-10-27 02:30"),
+            Ok(Utc.with_ymd_and_hms(2024, 10, 27, 0, 30, 0).unwrap())
+        );
+        // 02:00-03:00 is skipped on 2024-03-31.
+        assert!(parse_date("2024-03-31 02:30").is_err());
+        assert_eq!(
+            parse_date("2024-03-31 03:30"),
+            Ok(Utc.with_ymd_and_hms(2024, 3, 31, 1, 30, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn local_config_overlays_the_user_config() {
+        let user: Config =
+            toml::from_str("canvas_api_token = \"secret\"\nrepo = \"dd1337\"\ncourse = 1").unwrap();
+        let local: Config = toml::from_str("course = 2\nassignment = 3").unwrap();
+
+        let config = user.overlay(local);
+        assert_eq!(config.canvas_api_token.as_deref(), Some("secret"));
+        assert_eq!(config.repo.as_deref(), Some("dd1337"));
+        assert_eq!(config.course, Some(2));
+        assert_eq!(config.assignment, Some(3));
+    }
+
+    #[test]
+    fn local_config_may_not_redirect_the_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOCAL_CONFIG);
+        std::fs::write(&path, "canvas_base_url = \"https://evil.example.com\"").unwrap();
+        assert!(Config::read_local(&path).is_err());
+
+        std::fs::write(&path, "course = 2").unwrap();
+        assert_eq!(Config::read_local(&path).unwrap().course, Some(2));
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use zip::{write::SimpleFileOptions, CompressionMethod};
+
+use crate::{
+    canvas::{Match, MatchMethod},
+    remores::Booking,
+    RemoresDlError,
+};
+
+/// A machine readable record of a download, written as `manifest.json` in
+/// the download folder.
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub bookings: Vec<Entry>,
+}
+
+/// A booking, the submission matched to it and the files downloaded.
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub email: String,
+    pub time: DateTime<Utc>,
+    /// The REMORES sublist the booking was found in.
+    pub event: String,
+    pub canvas_name: Option<String>,
+    pub canvas_login: Option<String>,
+    pub submission_id: Option<u64>,
+    #[serde(rename = "match")]
+    pub method: Option<MatchMethod>,
+    /// Whether the submission was made after the due date.
+    pub late: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds_late: Option<u64>,
+    /// The current Canvas grade, only included when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grade: Option<Grade>,
+    /// Downloaded files, relative to the download folder.
+    pub files: Vec<File>,
+}
+
+/// A downloaded file.
+#[derive(Serialize, Debug)]
+pub struct File {
+    /// Relative to the download folder.
+    pub path: PathBuf,
+    /// The size of the file, missing in a dry run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Grade {
+    pub grade: Option<String>,
+    pub score: Option<f64>,
+    pub workflow_state: Option<String>,
+}
+
+impl Manifest {
+    /// Builds the manifest from the matched bookings, the files downloaded
+    /// for each submission id and their `sizes`. With `with_grades`, the
+    /// current grade of each submission is included.
+    pub fn new(
+        bookings: &HashMap<Booking, Option<Match>>,
+        files: &HashMap<u64, Vec<PathBuf>>,
+        sizes: &HashMap<PathBuf, u64>,
+        folder: &Path,
+        with_grades: bool,
+    ) -> Self {
+        let mut entries: Vec<Entry> = bookings
+            .iter()
+            .map(|(booking, m)| {
+                let submission = m.as_ref().map(|m| &m.submission);
+                let files = submission
+                    .and_then(|s| files.get(&s.id))
+                    .map(|paths| {
+                        paths
+                            .iter()
+                            .map(|p| File {
+                                path: p.strip_prefix(folder).unwrap_or(p).to_path_buf(),
+                                bytes: sizes.get(p).copied(),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                Entry {
+                    name: booking.name.clone(),
+                    email: booking.email.to_string(),
+                    time: booking.time,
+                    event: booking.event.clone(),
+                    canvas_name: submission.map(|s| s.user.name.clone()),
+                    canvas_login: submission.map(|s| s.user.identifier().to_string()),
+                    submission_id: submission.map(|s| s.id),
+                    method: m.as_ref().map(|m| m.method.clone()),
+                    late: submission.is_some_and(|s| s.is_late()),
+                    seconds_late: submission
+                        .filter(|s| s.is_late())
+                        .and_then(|s| s.seconds_late),
+                    grade: submission.filter(|_| with_grades).map(|s| Grade {
+                        grade: s.grade.clone(),
+                        score: s.score,
+                        workflow_state: s.workflow_state.clone(),
+                    }),
+                    files,
+                }
+            })
+            .collect();
+
+        entries.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.name.cmp(&b.name)));
+
+        Manifest { bookings: entries }
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), RemoresDlError> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Writes a zip archive at `path` with the downloaded files, under
+    /// their paths relative to `folder`, and the manifest as
+    /// `manifest.json`.
+    pub fn write_zip<P: AsRef<Path>>(&self, path: P, folder: &Path) -> Result<(), RemoresDlError> {
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path)?);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        // The same file can belong to several bookings, but is only added
+        // once since zip entry names must be unique.
+        let mut added = HashSet::new();
+        for file in self.bookings.iter().flat_map(|entry| &entry.files) {
+            for file in files_in(folder, &file.path)? {
+                if !added.insert(file.clone()) {
+                    continue;
+                }
+                let name = file
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                zip.start_file(name, options)?;
+                std::io::copy(&mut std::fs::File::open(folder.join(&file))?, &mut zip)?;
+            }
+        }
+
+        zip.start_file("manifest.json", options)?;
+        serde_json::to_writer_pretty(&mut zip, self)?;
+
+        zip.finish()?;
+        Ok(())
+    }
+
+    /// Writes a spreadsheet friendly summary with one row per booking.
+    pub fn write_csv<P: AsRef<Path>>(&self, path: P) -> Result<(), RemoresDlError> {
+        let mut writer = csv::Writer::from_path(path)?;
+        for entry in &self.bookings {
+            let (method, score) = match &entry.method {
+                Some(MatchMethod::Manual) => ("manual", None),
+                Some(MatchMethod::Email) => ("email", None),
+                Some(MatchMethod::LocalPart) => ("local_part", None),
+                Some(MatchMethod::Fuzzy { score, .. }) => ("fuzzy", Some(*score)),
+                None => ("", None),
+            };
+
+            writer.serialize(CsvRow {
+                time: entry.time,
+                name: &entry.name,
+                email: &entry.email,
+                canvas_name: entry.canvas_name.as_deref(),
+                canvas_login: entry.canvas_login.as_deref(),
+                submission_id: entry.submission_id,
+                match_method: method,
+                match_score: score,
+                late: entry.late,
+                seconds_late: entry.seconds_late,
+                files: entry
+                    .files
+                    .iter()
+                    .map(|file| file.path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(";"),
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the grades of the matched submissions as a CSV with the
+    /// columns `login_id,name,grade,score,workflow_state,comment`, which
+    /// can be edited and read back by the `grade` command.
+    pub fn write_grades_csv<P: AsRef<Path>>(&self, path: P) -> Result<(), RemoresDlError> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record([
+            "login_id",
+            "name",
+            "grade",
+            "score",
+            "workflow_state",
+            "comment",
+        ])?;
+        for entry in &self.bookings {
+            let (Some(login), Some(name)) = (&entry.canvas_login, &entry.canvas_name) else {
+                continue;
+            };
+            let grade = entry.grade.as_ref();
+
+            writer.write_record([
+                login.as_str(),
+                name.as_str(),
+                grade.and_then(|g| g.grade.as_deref()).unwrap_or_default(),
+                &grade
+                    .and_then(|g| g.score)
+                    .map(|score| score.to_string())
+                    .unwrap_or_default(),
+                grade
+                    .and_then(|g| g.workflow_state.as_deref())
+                    .unwrap_or_default(),
+                "",
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The files at `path`, relative to `folder`: `path` itself, or every file
+/// below it if it is a folder, like an extracted zip archive.
+fn files_in(folder: &Path, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if !folder.join(path).is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = vec![];
+    for entry in std::fs::read_dir(folder.join(path))? {
+        files.extend(files_in(folder, &path.join(entry?.file_name()))?);
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    time: DateTime<Utc>,
+    name: &'a str,
+    email: &'a str,
+    canvas_name: Option<&'a str>,
+    canvas_login: Option<&'a str>,
+    submission_id: Option<u64>,
+    match_method: &'a str,
+    match_score: Option<f64>,
+    late: bool,
+    seconds_late: Option<u64>,
+    files: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{canvas::Submission, remores::Email};
+
+    #[test]
+    fn canvas_login_falls_back_to_the_sis_id() {
+        let submission: Submission = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "workflow_state": "submitted",
+            "user": { "name": "Test Student", "sis_user_id": "u1test" },
+        }))
+        .unwrap();
+        let booking = Booking {
+            time: Utc::now(),
+            name: "Test Student".to_string(),
+            email: Email::OtherEmail("test@gmail.com".to_string()),
+            event: "lab1".to_string(),
+        };
+        let bookings = HashMap::from([(
+            booking,
+            Some(Match {
+                submission,
+                method: MatchMethod::Fuzzy {
+                    score: 1.0,
+                    ambiguous: false,
+                },
+            }),
+        )]);
+
+        let manifest = Manifest::new(
+            &bookings,
+            &HashMap::new(),
+            &HashMap::new(),
+            Path::new("downloads"),
+            false,
+        );
+        assert_eq!(manifest.bookings[0].canvas_login.as_deref(), Some("u1test"));
+    }
+}
@@ -1,149 +1,552 @@
 use core::fmt;
-use std::hash::Hash;
+use std::{collections::HashSet, path::PathBuf, time::Duration};
 
-use chrono::{DateTime, NaiveDateTime, Utc};
-use scraper::{Html, Selector};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use ego_tree::NodeRef;
+use futures::{stream, StreamExt};
+use scraper::{ElementRef, Html, Node, Selector};
+use serde::Serialize;
+use tracing::{debug, info, warn};
 
-const REMORES_URL: &str = "https://www.csc.kth.se/cgi-bin/bokning/remores1.4/server/decoder";
+pub const DEFAULT_REMORES_URL: &str =
+    "https://www.csc.kth.se/cgi-bin/bokning/remores1.4/server/decoder";
+
+/// The time zone REMORES shows booking times in.
+pub const TIMEZONE: Tz = chrono_tz::Europe::Stockholm;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many sublists are fetched at the same time by default.
+pub const DEFAULT_CONCURRENCY: usize = 3;
+
+/// How long cached REMORES pages are used before they are fetched again.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Errors fetching or parsing bookings from REMORES.
+#[derive(Debug, thiserror::Error)]
+pub enum RemoresError {
+    #[error(
+        "REMORES repository {repository} has no booking lists, is the repository name correct?"
+    )]
+    NoBookingLists { repository: String },
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    /// A booking list is not in the expected format.
+    #[error("{0}")]
+    Parse(String),
+    /// REMORES answered with a page that is not a booking list, e.g. a
+    /// maintenance notice or an error page.
+    #[error(
+        "Booking list {sub_list} in REMORES repository {repository} could not be read: {reason}. The page does not look like a booking list, REMORES may be down or have changed"
+    )]
+    UnexpectedPage {
+        repository: String,
+        sub_list: String,
+        reason: String,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
 
 pub struct Remores {
     client: reqwest::Client,
     repository: String,
+    url: String,
+    timeout: Duration,
+    concurrency: usize,
+    cache: Option<Cache>,
+    /// Read pages from files in this folder instead of REMORES.
+    fixtures: Option<PathBuf>,
+}
+
+/// An on-disk cache of the raw REMORES pages, keyed by request parameters.
+struct Cache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl Cache {
+    /// The file of the page for `url` and `params`, named by their 64-bit
+    /// FNV-1a hash. Unlike `DefaultHasher`, it stays the same between Rust
+    /// versions, so an upgrade doesn't orphan the cached pages.
+    fn path(&self, url: &str, params: &[(&str, &str)]) -> PathBuf {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let parts = params.iter().flat_map(|(key, value)| [*key, *value]);
+        for part in std::iter::once(url).chain(parts) {
+            // The separator keeps e.g. ("ab", "c") and ("a", "bc") apart.
+            for byte in part.bytes().chain([0]) {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+        self.dir.join(format!("{:016x}.html", hash))
+    }
+
+    async fn get(&self, url: &str, params: &[(&str, &str)]) -> Option<String> {
+        let path = self.path(url, params);
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+        if modified.elapsed().ok()? > self.ttl {
+            return None;
+        }
+        tokio::fs::read_to_string(path).await.ok()
+    }
+
+    async fn put(
+        &self,
+        url: &str,
+        params: &[(&str, &str)],
+        content: &str,
+    ) -> Result<(), RemoresError> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.path(url, params), content).await?;
+        Ok(())
+    }
+}
+
+/// The default location of the REMORES cache, in the user's cache folder.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("remores-dl"))
 }
 
 type KTHId = String;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Serializes as the plain email address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(untagged)]
 pub enum Email {
     KTHEmail(KTHId),
     OtherEmail(String),
 }
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize)]
 pub struct Booking {
+    /// The start of the booked slot. Use `TIMEZONE` to show it the way
+    /// REMORES does.
     pub time: DateTime<Utc>,
     pub name: String,
     pub email: Email,
+    /// The REMORES sublist (event) the booking was found in.
+    pub event: String,
+}
+
+impl Booking {
+    /// Identifies the booked slot and student, regardless of which sublist
+    /// the booking was found in.
+    fn key(&self) -> (DateTime<Utc>, String, Email) {
+        (self.time, self.name.clone(), self.email.clone())
+    }
 }
 
 impl Remores {
     pub fn new(repository: String) -> Self {
-        let client = reqwest::Client::new();
-        Remores { client, repository }
+        Self::with_url(repository, DEFAULT_REMORES_URL.to_string())
     }
 
-    pub async fn get_bookings_for(&self, kth_id: String) -> Result<Vec<Booking>, anyhow::Error> {
-        let overview = self
-            .client
-            .get(REMORES_URL)
-            .query(&[
-                ("request:overview", "yes"),
-                ("repository", &self.repository),
-                ("shownameemail", "yes"),
-            ])
-            .send()
+    /// Creates a client for `repository` on the REMORES decoder at `url`.
+    pub fn with_url(repository: String, url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::USER_AGENT)
+            .build()
+            .unwrap();
+        Remores {
+            client,
+            repository,
+            url,
+            timeout: DEFAULT_TIMEOUT,
+            concurrency: DEFAULT_CONCURRENCY,
+            cache: None,
+            fixtures: None,
+        }
+    }
+
+    /// Caches the fetched REMORES pages in `dir`, and reuses them for
+    /// `ttl` instead of asking the server again.
+    pub fn with_cache(mut self, dir: PathBuf, ttl: Duration) -> Self {
+        self.cache = Some(Cache { dir, ttl });
+        self
+    }
+
+    /// Reads the pages from HTML files in `dir` instead of asking REMORES:
+    /// `remores/overview.html` for the repository overview, and
+    /// `remores/<sublist>.html` for each sublist.
+    pub fn with_fixtures(mut self, dir: PathBuf) -> Self {
+        self.fixtures = Some(dir);
+        self
+    }
+
+    /// Sets how many sublists are fetched at the same time, at least one.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets how long to wait for the REMORES server before giving up on a
+    /// request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn get_bookings_for(&self, kth_id: String) -> Result<Vec<Booking>, RemoresError> {
+        self.get_bookings_for_many(&[kth_id]).await
+    }
+
+    /// Finds the bookings in the sublists of any of the reviewers in
+    /// `kth_ids`. Sublists and bookings shared between reviewers are only
+    /// included once. Fails if any sublist can't be fetched or parsed.
+    pub async fn get_bookings_for_many(
+        &self,
+        kth_ids: &[String],
+    ) -> Result<Vec<Booking>, RemoresError> {
+        let sub_lists = self.get_sublists_for(kth_ids).await?;
+        let (bookings, _) = self.get_bookings_in(sub_lists, false).await?;
+        info!(bookings = bookings.len(), "Found bookings");
+        Ok(bookings)
+    }
+
+    /// Like `get_bookings_for_many`, but skips the sublists that can't be
+    /// fetched or parsed, and returns them with the error instead.
+    pub async fn get_bookings_for_many_skipping(
+        &self,
+        kth_ids: &[String],
+    ) -> Result<(Vec<Booking>, Vec<(String, RemoresError)>), RemoresError> {
+        let sub_lists = self.get_sublists_for(kth_ids).await?;
+        let (bookings, skipped) = self.get_bookings_in(sub_lists, true).await?;
+        info!(
+            bookings = bookings.len(),
+            skipped = skipped.len(),
+            "Found bookings"
+        );
+        Ok((bookings, skipped))
+    }
+
+    /// Finds the bookings in every sublist of the repository. Sublists that
+    /// can't be fetched or parsed are skipped with a warning.
+    pub async fn get_all_bookings(&self) -> Result<Vec<Booking>, RemoresError> {
+        let sub_lists = self.get_sublists().await?;
+        let (bookings, skipped) = self.get_bookings_in(sub_lists, true).await?;
+        for (sub_list, e) in skipped {
+            warn!("Skipping sublist {}: {}", sub_list, e);
+        }
+        Ok(bookings)
+    }
+
+    /// Lists the sublists of any of the reviewers in `kth_ids`.
+    async fn get_sublists_for(&self, kth_ids: &[String]) -> Result<Vec<String>, RemoresError> {
+        let sub_lists: Vec<String> = self
+            .get_sublists()
             .await?
-            .text()
+            .into_iter()
+            .filter(|value| {
+                kth_ids
+                    .iter()
+                    .any(|kth_id| value.ends_with(kth_id.as_str()))
+            })
+            .collect();
+
+        info!(
+            sub_lists = sub_lists.len(),
+            "Found sublists for {:?}", kth_ids
+        );
+        Ok(sub_lists)
+    }
+
+    /// Fetches the sublists concurrently, and returns their bookings sorted
+    /// by time. With `keep_going`, sublists that can't be fetched or parsed
+    /// are returned with their error, otherwise the first error is.
+    async fn get_bookings_in(
+        &self,
+        sub_lists: Vec<String>,
+        keep_going: bool,
+    ) -> Result<(Vec<Booking>, Vec<(String, RemoresError)>), RemoresError> {
+        let mut results = stream::iter(sub_lists)
+            .map(|sub_list| async move {
+                let result = self.get_sublist(&sub_list).await;
+                (sub_list, result)
+            })
+            .buffer_unordered(self.concurrency);
+
+        let mut bookings = vec![];
+        let mut skipped = vec![];
+        while let Some((sub_list, result)) = results.next().await {
+            match result {
+                Ok(sub_list_bookings) => bookings.extend(sub_list_bookings),
+                Err(e) if keep_going => skipped.push((sub_list, e)),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Sublists finish in any order, so sort before removing duplicates
+        // to always keep the same one.
+        bookings.sort_by(|a, b| (a.time, &a.name, &a.event).cmp(&(b.time, &b.name, &b.event)));
+        let mut seen = HashSet::new();
+        bookings.retain(|booking| seen.insert(booking.key()));
+        Ok((bookings, skipped))
+    }
+
+    /// Checks whether the repository exists, i.e. has any booking lists.
+    /// REMORES has no way to list the repositories, so this is the way to
+    /// confirm a guessed name.
+    pub async fn repository_exists(&self) -> Result<bool, RemoresError> {
+        match self.get_sublists().await {
+            Ok(_) => Ok(true),
+            Err(RemoresError::NoBookingLists { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lists the ids of all sublists in the repository overview, without
+    /// duplicates.
+    async fn get_sublists(&self) -> Result<Vec<String>, RemoresError> {
+        let params = [
+            ("request:overview", "yes"),
+            ("repository", self.repository.as_str()),
+            ("shownameemail", "yes"),
+        ];
+        let overview = self
+            .fetch(&params, self.client.get(&self.url).query(&params))
             .await?;
 
         let document = Html::parse_fragment(&overview);
         let selector = Selector::parse("input").unwrap();
 
-        let sub_lists: Vec<&str> = document
+        let mut values: Vec<String> = document
             .select(&selector)
             .filter_map(|el| el.attr("value"))
-            .filter(|value| value.ends_with(&kth_id))
+            .map(str::to_string)
             .collect();
-
-        let mut bookings = vec![];
-        for sub_list in sub_lists {
-            bookings.extend(self.get_sublist(sub_list).await?);
+        if values.is_empty() {
+            return Err(RemoresError::NoBookingLists {
+                repository: self.repository.clone(),
+            });
         }
 
-        Ok(bookings)
+        let mut seen = HashSet::new();
+        values.retain(|value| seen.insert(value.clone()));
+
+        Ok(values)
     }
 
-    async fn get_sublist(&self, sub_list: &str) -> Result<Vec<Booking>, anyhow::Error> {
-        let mut bookings = vec![];
+    /// Sends `request` and returns the body, or the cached body of an
+    /// earlier request with the same `params`.
+    async fn fetch(
+        &self,
+        params: &[(&str, &str)],
+        request: reqwest::RequestBuilder,
+    ) -> Result<String, RemoresError> {
+        if let Some(dir) = &self.fixtures {
+            let name = params
+                .iter()
+                .find(|(key, _)| *key == "event")
+                .map_or("overview", |(_, event)| event);
+            let file = dir.join("remores").join(format!("{}.html", name));
+            debug!(file = %file.display(), "Reading fixture");
+            return Ok(tokio::fs::read_to_string(file).await?);
+        }
+
+        if let Some(cache) = &self.cache {
+            if let Some(content) = cache.get(&self.url, params).await {
+                debug!(url = self.url, ?params, "Using cached REMORES page");
+                return Ok(content);
+            }
+        }
 
+        debug!(url = self.url, ?params, "Fetching REMORES page");
+        let resp = request.timeout(self.timeout).send().await?;
+        debug!(status = %resp.status(), "REMORES responded");
+        // Error pages must not be cached and then used as the real page.
+        let content = resp.error_for_status()?.text().await?;
+
+        if let Some(cache) = &self.cache {
+            // A broken cache should never stop us from using the response.
+            let _ = cache.put(&self.url, params, &content).await;
+        }
+
+        Ok(content)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_sublist(&self, sub_list: &str) -> Result<Vec<Booking>, RemoresError> {
         let params = [
             ("event", sub_list),
             ("request:reservation-view", "+Hämta+bokningslista+"),
             ("shownameemail", "yes"),
-            ("repository", &self.repository),
+            ("repository", self.repository.as_str()),
         ];
         let content = self
-            .client
-            .post(REMORES_URL)
-            .form(&params)
-            .send()
-            .await?
-            .text()
+            .fetch(&params, self.client.post(&self.url).form(&params))
             .await?;
 
-        let document = Html::parse_fragment(&content);
-        let date_selector = Selector::parse("br").unwrap();
+        let unexpected = |reason: String| RemoresError::UnexpectedPage {
+            repository: self.repository.clone(),
+            sub_list: sub_list.to_string(),
+            reason,
+        };
+        if content.trim().is_empty() {
+            return Err(unexpected("the page is empty".to_string()));
+        }
+        let bookings = parse_sublist(sub_list, &content).map_err(|e| match e {
+            RemoresError::Parse(reason) => unexpected(reason),
+            e => e,
+        })?;
+        debug!(bookings = bookings.len(), "Parsed sublist");
+        Ok(bookings)
+    }
+}
+
+/// Formats of the date heading a reservation list, tried in order. Two
+/// digit years come first, since `%Y` also accepts them.
+const DATE_FORMATS: [&str; 2] = ["%y-%m-%d", "%Y-%m-%d"];
 
-        let date = document
-            .select(&date_selector)
-            .next()
-            .and_then(|e| e.next_sibling())
-            .and_then(|e| e.next_sibling())
-            .and_then(|e| e.first_child())
-            .ok_or(anyhow::anyhow!("No date"))?
-            .value()
-            .as_text()
-            .ok_or(anyhow::anyhow!("No date text"))?
-            .trim();
-
-        let selector = Selector::parse("input[name=reservation]").unwrap();
-
-        for el in document.select(&selector) {
-            let time: &str = el
-                .prev_sibling()
-                .and_then(|e| e.prev_sibling())
-                .and_then(|e| e.first_child())
-                .and_then(|e| e.value().as_text())
-                .ok_or(anyhow::anyhow!("No time for input"))?;
-
-            let name_node = el
-                .next_sibling()
-                .ok_or(anyhow::anyhow!("No name for input"))?;
-
-            let email: &str = name_node
-                .next_sibling()
-                .and_then(|e| e.first_child())
-                .and_then(|e| e.first_child())
-                .ok_or(anyhow::anyhow!("No email for input"))?
-                .value()
-                .as_text()
-                .ok_or(anyhow::anyhow!("No email text for input"))?;
-
-            let name: &str = name_node
-                .value()
-                .as_text()
-                .ok_or(anyhow::anyhow!("No text for name element"))?
-                .trim_end_matches("(")
-                .trim();
-
-            let datetime = format!("{} {}", date, time);
-            let time = NaiveDateTime::parse_from_str(&datetime, "%y-%m-%d %H:%M")?;
-
-            let parsed_email = match email.to_string() {
-                e if e.ends_with("@kth.se") => Email::KTHEmail(e),
+/// Formats of the time of a slot.
+const TIME_FORMATS: [&str; 2] = ["%H:%M", "%H:%M:%S"];
+
+/// Formats of the date and slot time together, tried in order.
+const DATETIME_FORMATS: [&str; 4] = [
+    "%y-%m-%d %H:%M",
+    "%Y-%m-%d %H:%M",
+    "%y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M:%S",
+];
+
+/// Parses the bookings in a REMORES reservation list.
+///
+/// Each booked slot is an `input[name=reservation]`, preceded by the slot
+/// time and followed by the name and an element with the email of each
+/// student in the slot. A slot with several students gives one booking
+/// per student.
+/// The surrounding nodes are searched by content rather than by exact
+/// position, so that changes in whitespace or markup don't break parsing.
+fn parse_sublist(event: &str, content: &str) -> Result<Vec<Booking>, RemoresError> {
+    let mut bookings = vec![];
+
+    let document = Html::parse_fragment(content);
+
+    let date = document
+        .root_element()
+        .text()
+        .map(str::trim)
+        .find(|text| {
+            DATE_FORMATS
+                .iter()
+                .any(|format| NaiveDate::parse_from_str(text, format).is_ok())
+        })
+        .ok_or_else(|| RemoresError::Parse("no date found".to_string()))?;
+
+    let selector = Selector::parse("input[name=reservation]").unwrap();
+
+    for el in document.select(&selector) {
+        let time = el
+            .prev_siblings()
+            .take_while(|node| !is_reservation(node))
+            .map(node_text)
+            .find(|text| {
+                TIME_FORMATS
+                    .iter()
+                    .any(|format| NaiveTime::parse_from_str(text, format).is_ok())
+            })
+            .ok_or_else(|| RemoresError::Parse("no time found for a booked slot".to_string()))?;
+
+        // A slot can be booked by several students, e.g. for paired oral
+        // exams, each given as a name followed by their email.
+        let mut students = vec![];
+        let mut name = None;
+        for node in el.next_siblings().take_while(|node| !is_reservation(node)) {
+            if let Some(text) = node.value().as_text() {
+                let text = text.trim_matches(|c: char| c.is_whitespace() || "(),".contains(c));
+                if !text.is_empty() {
+                    name = Some(text.to_string());
+                }
+            } else if node.value().is_element() {
+                let text = node_text(node);
+                if text.contains('@') {
+                    let name = name.take().ok_or_else(|| {
+                        RemoresError::Parse(format!("no name found for email {}", text))
+                    })?;
+                    students.push((name, text));
+                }
+            }
+        }
+        if students.is_empty() {
+            return Err(RemoresError::Parse(
+                "no name or email found for a booked slot".to_string(),
+            ));
+        }
+
+        let datetime = format!("{} {}", date, time);
+        let time = DATETIME_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(&datetime, format).ok())
+            .ok_or_else(|| RemoresError::Parse(format!("invalid time {}", datetime)))?;
+        // When the clocks go back an hour, the earlier of the two
+        // possible instants is used.
+        let time = TIMEZONE
+            .from_local_datetime(&time)
+            .earliest()
+            .ok_or_else(|| {
+                RemoresError::Parse(format!("{} does not exist in {}", datetime, TIMEZONE))
+            })?
+            .with_timezone(&Utc);
+
+        for (name, email) in students {
+            let parsed_email = match email {
+                e if e.to_ascii_lowercase().ends_with("@kth.se") => Email::KTHEmail(e),
                 e => Email::OtherEmail(e),
             };
 
-            let booking = Booking {
-                time: DateTime::from_naive_utc_and_offset(time, Utc),
-                name: name.to_string(),
+            bookings.push(Booking {
+                time,
+                name,
                 email: parsed_email,
-            };
+                event: event.to_string(),
+            });
+        }
+    }
+
+    Ok(bookings)
+}
+
+fn is_reservation(node: &NodeRef<Node>) -> bool {
+    node.value()
+        .as_element()
+        .is_some_and(|el| el.name() == "input" && el.attr("name") == Some("reservation"))
+}
+
+/// The trimmed text of a node and all its descendants.
+fn node_text(node: NodeRef<Node>) -> String {
+    match ElementRef::wrap(node) {
+        Some(el) => el.text().collect::<String>().trim().to_string(),
+        None => node
+            .value()
+            .as_text()
+            .map(|text| text.trim().to_string())
+            .unwrap_or_default(),
+    }
+}
 
-            bookings.push(booking);
+impl Email {
+    /// `kth` for KTH emails, `other` for everything else.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Email::KTHEmail(_) => "kth",
+            Email::OtherEmail(_) => "other",
         }
+    }
 
-        Ok(bookings)
+    /// Whether this is the KTH email of the Canvas user with `login`,
+    /// ignoring case.
+    pub fn is_kth_login(&self, login: &str) -> bool {
+        match self {
+            Email::KTHEmail(email) => email.eq_ignore_ascii_case(login),
+            Email::OtherEmail(_) => false,
+        }
+    }
+
+    /// The part before the `@`, e.g. `asalamon` for `asalamon@kth.se`.
+    pub fn local_part(&self) -> &str {
+        let (Email::KTHEmail(email) | Email::OtherEmail(email)) = self;
+        email.split('@').next().unwrap_or_default()
     }
 }
 
@@ -155,3 +558,229 @@ impl fmt::Display for Email {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn stockholm(datetime: &str) -> DateTime<Utc> {
+        let time = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M").unwrap();
+        TIMEZONE
+            .from_local_datetime(&time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    /// A sublist with one slot at `time` on `date`, booked by Anna Berg.
+    fn one_slot(date: &str, time: &str) -> String {
+        format!(
+            "<b>{}</b><br><b>{}</b> <input type=\"radio\" name=\"reservation\" value=\"1\"> \
+             Anna Berg (<a href=\"mailto:aberg@kth.se\"><tt>aberg@kth.se</tt></a>)",
+            date, time
+        )
+    }
+
+    #[test]
+    fn parses_sublist_fixture() {
+        let content = include_str!("../tests/fixtures/remores/lab1-asalamon.html");
+        let bookings = parse_sublist("lab1-asalamon", content).unwrap();
+
+        let booking = |time: &str, name: &str, email: Email| Booking {
+            time: stockholm(time),
+            name: name.to_string(),
+            email,
+            event: "lab1-asalamon".to_string(),
+        };
+        assert_eq!(
+            bookings,
+            [
+                booking(
+                    "2024-05-13 10:00",
+                    "Anna Berg",
+                    Email::KTHEmail("aberg@kth.se".to_string())
+                ),
+                booking(
+                    "2024-05-13 10:20",
+                    "Erik Björk",
+                    Email::OtherEmail("erik.bjork@example.com".to_string())
+                ),
+                booking(
+                    "2024-05-13 11:00",
+                    "Johan Svensson",
+                    Email::KTHEmail("johansv@kth.se".to_string())
+                ),
+                booking(
+                    "2024-05-13 11:20",
+                    "Lisa Ek",
+                    Email::KTHEmail("lisaek@kth.se".to_string())
+                ),
+                booking(
+                    "2024-05-13 11:20",
+                    "Omar Nilsson",
+                    Email::KTHEmail("omarn@kth.se".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn paired_slot_gives_a_booking_per_student() {
+        let content = include_str!("../tests/fixtures/remores/lab1-asalamon.html");
+        let bookings = parse_sublist("lab1-asalamon", content).unwrap();
+
+        let paired: Vec<_> = bookings
+            .iter()
+            .filter(|booking| booking.time == stockholm("2024-05-13 11:20"))
+            .map(|booking| booking.name.as_str())
+            .collect();
+        assert_eq!(paired, ["Lisa Ek", "Omar Nilsson"]);
+    }
+
+    #[test]
+    fn parses_every_date_and_time_format() {
+        // One case per pairing of DATE_FORMATS and TIME_FORMATS, which
+        // together cover DATETIME_FORMATS.
+        let cases = [
+            ("24-05-13", "10:00"),
+            ("2024-05-13", "10:00"),
+            ("24-05-13", "10:00:00"),
+            ("2024-05-13", "10:00:00"),
+        ];
+        for (date, time) in cases {
+            let bookings = parse_sublist("lab1-asalamon", &one_slot(date, time))
+                .unwrap_or_else(|e| panic!("{} {}: {}", date, time, e));
+            assert_eq!(
+                bookings[0].time,
+                stockholm("2024-05-13 10:00"),
+                "{} {}",
+                date,
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn slot_skipped_by_spring_forward_is_an_error() {
+        // 02:00-03:00 doesn't exist in Stockholm on 2024-03-31.
+        let result = parse_sublist("lab1-asalamon", &one_slot("24-03-31", "02:30"));
+        assert!(matches!(result, Err(RemoresError::Parse(_))));
+    }
+
+    #[test]
+    fn slot_repeated_by_fall_back_uses_the_earlier_instant() {
+        // 02:30 happens twice in Stockholm on 2024-10-27, first in CEST.
+        let bookings = parse_sublist("lab1-asalamon", &one_slot("24-10-27", "02:30")).unwrap();
+        assert_eq!(
+            bookings[0].time,
+            Utc.with_ymd_and_hms(2024, 10, 27, 0, 30, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn kth_domain_ignores_case() {
+        let content = one_slot("24-05-13", "10:00").replace("aberg@kth.se", "Aberg@KTH.se");
+        let bookings = parse_sublist("lab1-asalamon", &content).unwrap();
+        assert_eq!(
+            bookings[0].email,
+            Email::KTHEmail("Aberg@KTH.se".to_string())
+        );
+        assert!(bookings[0].email.is_kth_login("aberg@kth.se"));
+    }
+
+    #[tokio::test]
+    async fn cache_entries_expire_and_are_refreshed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache {
+            dir: dir.path().to_path_buf(),
+            ttl: Duration::from_secs(60),
+        };
+        let params = [("event", "lab1-asalamon")];
+
+        assert_eq!(cache.get(DEFAULT_REMORES_URL, &params).await, None);
+        cache
+            .put(DEFAULT_REMORES_URL, &params, "old")
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.get(DEFAULT_REMORES_URL, &params).await.as_deref(),
+            Some("old")
+        );
+
+        // Age the entry past the TTL.
+        let file = std::fs::File::options()
+            .write(true)
+            .open(cache.path(DEFAULT_REMORES_URL, &params))
+            .unwrap();
+        file.set_modified(std::time::SystemTime::now() - Duration::from_secs(120))
+            .unwrap();
+        assert_eq!(cache.get(DEFAULT_REMORES_URL, &params).await, None);
+
+        cache
+            .put(DEFAULT_REMORES_URL, &params, "new")
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.get(DEFAULT_REMORES_URL, &params).await.as_deref(),
+            Some("new")
+        );
+    }
+
+    #[test]
+    fn cache_path_is_stable() {
+        let cache = Cache {
+            dir: PathBuf::from("cache"),
+            ttl: DEFAULT_CACHE_TTL,
+        };
+        let params = [("event", "lab1-asalamon")];
+        assert_eq!(
+            cache.path(DEFAULT_REMORES_URL, &params),
+            cache.path(DEFAULT_REMORES_URL, &params)
+        );
+        assert_ne!(
+            cache.path(DEFAULT_REMORES_URL, &params),
+            cache.path(DEFAULT_REMORES_URL, &[("event", "lab2-asalamon")])
+        );
+        assert_eq!(
+            cache.path("", &[]),
+            PathBuf::from("cache").join("af63bd4c8601b7df.html")
+        );
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_not_cached() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let overview = r#"<input type="radio" name="event" value="lab1-asalamon">"#;
+            let responses = [
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndown"
+                    .to_string(),
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    overview.len(),
+                    overview
+                ),
+            ];
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4096];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let remores = Remores::with_url("dd1337".to_string(), format!("http://{}", addr))
+            .with_cache(dir.path().to_path_buf(), DEFAULT_CACHE_TTL);
+
+        assert!(matches!(
+            remores.get_sublists().await,
+            Err(RemoresError::Http(_))
+        ));
+        assert_eq!(remores.get_sublists().await.unwrap(), ["lab1-asalamon"]);
+    }
+}